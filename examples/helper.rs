@@ -0,0 +1,37 @@
+use jelly_uidmng as uidmng;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::result::Result;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // helper として起動された場合はここで helper として動作して終了する
+    uidmng::run_helper_if_requested();
+
+    // sudo で起動した helper を使い回す
+    uidmng::start_helper()?;
+
+    let filename = "/tmp/test_helper_output.txt";
+    for i in 0..10 {
+        uidmng::write_sudo(filename, format!("{}\n", i).as_bytes())?;
+        let data = uidmng::read_sudo(filename)?;
+        print!("{}", String::from_utf8_lossy(&data));
+    }
+    let metadata = uidmng::metadata_sudo(filename)?;
+    println!("uid={} mode={:o}", metadata.uid, metadata.permissions());
+
+    // helper が開いたファイルの fd を受け取って使う
+    let mut file = uidmng::open_sudo(filename, uidmng::OpenOptions::new().append(true))?;
+    file.write_all(b"appended\n")?;
+    drop(file);
+
+    uidmng::stop_helper();
+
+    // helper が起動していなくても、その場で helper を起動して開く
+    let mut file = uidmng::open_sudo(filename, uidmng::OpenOptions::new().read(true))?;
+    let mut data = String::new();
+    file.read_to_string(&mut data)?;
+    print!("{}", data);
+
+    uidmng::command_sudo("rm", [filename])?;
+    Ok(())
+}
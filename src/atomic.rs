@@ -0,0 +1,313 @@
+use crate::{elevator, giveback};
+use crate::{is_root, with_root, with_user, Elevator, Error};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{fchown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Writes binary data to a file atomically.
+///
+/// The data is written to a temporary file in the same directory, which is synced and then
+/// renamed over `filename`, so the file never appears partially written even on power loss.
+/// The owner, group and permissions of an existing file are kept.
+/// Without root, an owner or group the caller cannot set is left as the caller's.
+pub fn write_atomic(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    // シンボリックリンクの場合はリンク自体ではなくリンク先を置き換える
+    let target = fs::canonicalize(filename).unwrap_or_else(|_| filename.to_path_buf());
+    let existing = match fs::metadata(&target) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(Error::io(&target, e)),
+    };
+
+    // 既存のファイルを置き換える場合はパーミッションを合わせるまで他から読めないようにする
+    let mode = if existing.is_some() { 0o600 } else { 0o666 };
+    let (temp, file) = create_temp(&target, mode)?;
+
+    // 一時ファイルはこの呼び出しで作ったものだけを消す
+    let result = write_temp(&target, &temp, file, existing, data);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Creates a new temporary file next to `filename` with `O_EXCL`.
+///
+/// The name is unique to each call, so concurrent writes to the same file do not share it.
+fn create_temp(filename: &Path, mode: u32) -> Result<(PathBuf, File), Error> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let mut name = OsString::from(".");
+        name.push(filename.file_name().unwrap_or(OsStr::new("file")));
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        name.push(format!(".{}.{}.tmp", std::process::id(), count));
+        let temp = filename.with_file_name(name);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&temp)
+        {
+            Ok(file) => return Ok((temp, file)),
+            // 以前のプロセスが残したファイルと重なった場合は名前を変える
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(Error::io(temp, e)),
+        }
+    }
+}
+
+/// Writes `data` to the created `temp` and renames it to `filename`.
+fn write_temp(
+    filename: &Path,
+    temp: &Path,
+    mut file: File,
+    existing: Option<fs::Metadata>,
+    data: &[u8],
+) -> Result<(), Error> {
+    let with_temp = |e| Error::io(temp, e);
+    file.write_all(data).map_err(with_temp)?;
+
+    // 既存のファイルの所有者とパーミッションを引き継ぐ (新規の場合は umask に従う)
+    if let Some(metadata) = existing {
+        // 変える必要の無い id は変えない (user 権限でも自分の所有のファイルは置き換えられるように)
+        let created = file.metadata().map_err(with_temp)?;
+        let uid = Some(metadata.uid()).filter(|&uid| uid != created.uid());
+        let gid = Some(metadata.gid()).filter(|&gid| gid != created.gid());
+        if uid.is_some() || gid.is_some() {
+            match fchown(&file, uid, gid) {
+                Ok(()) => {}
+                // user 権限では引き継げない所有者は諦めて、自分の所有のまま置き換える
+                Err(e) if e.raw_os_error() == Some(nix::libc::EPERM) && !is_root() => {}
+                Err(e) => return Err(with_temp(e)),
+            }
+        }
+        file.set_permissions(fs::Permissions::from_mode(metadata.mode() & 0o7777))
+            .map_err(with_temp)?;
+    }
+    file.sync_all().map_err(with_temp)?;
+    drop(file);
+
+    fs::rename(temp, filename).map_err(|e| Error::io(filename, e))?;
+
+    // rename 自体を確定させるためにディレクトリも同期する
+    let dir = match filename.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| Error::io(dir, e))
+}
+
+// elevator 経由で実行するスクリプト
+// stat -c などを使うので GNU coreutils が必要
+// $1: file
+const WRITE_ATOMIC_SCRIPT: &str = r#"set -e
+file=$(readlink -f -- "$1") || file=$1
+dir=$(dirname -- "$file")
+temp=$(mktemp -- "$dir/.jelly_uidmng.XXXXXX")
+trap 'rm -f -- "$temp"' EXIT
+cat > "$temp"
+if [ -e "$file" ]; then
+    chown -- "$(stat -c %u:%g -- "$file")" "$temp"
+    chmod -- "$(stat -c %a -- "$file")" "$temp"
+else
+    chmod -- "$(printf '%o' $((0666 & ~0$(umask))))" "$temp"
+fi
+sync -- "$temp" 2>/dev/null || sync
+mv -f -- "$temp" "$file"
+trap - EXIT
+sync -- "$dir" 2>/dev/null || sync
+"#;
+
+/// Writes binary data to a file atomically through `elevator` (or the configured one).
+fn write_atomic_elevated(
+    elevator: Option<&Arc<dyn Elevator>>,
+    filename: &Path,
+    data: &[u8],
+) -> Result<(), Error> {
+    elevator::run_script(
+        elevator,
+        WRITE_ATOMIC_SCRIPT,
+        &[filename.as_os_str()],
+        Some(data),
+    )?;
+    Ok(())
+}
+
+/// Writes binary data to a file atomically using `sudo` permissions.
+pub fn write_atomic_sudo(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    giveback::track(filename, || write_atomic_elevated(None, filename, data))
+}
+
+/// Writes binary data to a file atomically using user permissions.
+pub fn write_atomic_user(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_user(|| write_atomic(filename, data))
+}
+
+/// Writes binary data to a file atomically using root permissions, or `sudo` if allowed.
+pub fn write_atomic_root(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    giveback::track(filename, || {
+        with_root(
+            || write_atomic(filename, data),
+            || write_atomic_sudo(filename, data),
+        )
+    })
+}
+
+/// Writes binary data to a file atomically and tries to use root permissions if that fails.
+pub fn write_atomic_try(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    giveback::track(filename, || {
+        let result = write_atomic(filename, data);
+        if result.is_err() && !is_root() {
+            write_atomic_root(filename, data)
+        } else {
+            result
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{has_root, metadata, read, Sudo};
+    use nix::unistd::{Gid, Uid};
+
+    #[test]
+    fn test_write_atomic() -> Result<(), Box<dyn std::error::Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_atomic",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let dir = std::env::temp_dir().join(format!("jelly_uidmng_atomic_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        for sudo in [false, true] {
+            let write = |path: &Path, data: &[u8]| {
+                if sudo {
+                    write_atomic_elevated(Some(&elevator), path, data)
+                } else {
+                    write_atomic(path, data)
+                }
+            };
+
+            // 既存のファイルの所有者とパーミッションが引き継がれる
+            let file_name = dir.join("config");
+            std::fs::write(&file_name, b"old")?;
+            std::fs::set_permissions(&file_name, std::fs::Permissions::from_mode(0o640))?;
+            if has_root() {
+                std::os::unix::fs::chown(&file_name, Some(1234), Some(5678))?;
+            }
+            write(&file_name, b"new")?;
+            assert_eq!(read(&file_name)?, b"new");
+            let metadata = metadata(&file_name)?;
+            assert_eq!(metadata.permissions(), 0o640);
+            if has_root() {
+                assert_eq!(metadata.uid, Uid::from_raw(1234));
+                assert_eq!(metadata.gid, Gid::from_raw(5678));
+            }
+
+            // シンボリックリンクはリンク先が置き換えられる
+            let link = dir.join("link");
+            let _ = std::fs::remove_file(&link);
+            std::os::unix::fs::symlink(&file_name, &link)?;
+            write(&link, b"linked")?;
+            assert!(std::fs::symlink_metadata(&link)?.file_type().is_symlink());
+            assert_eq!(read(&file_name)?, b"linked");
+
+            // 新規作成と一時ファイルが残らないこと
+            let file_name = dir.join("new file");
+            let _ = std::fs::remove_file(&file_name);
+            write(&file_name, b"created")?;
+            assert_eq!(read(&file_name)?, b"created");
+            assert!(write(&dir.join("missing/file"), b"error").is_err());
+            let mut names: Vec<_> = std::fs::read_dir(&dir)?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<Result<_, _>>()?;
+            names.sort();
+            assert_eq!(names, ["config", "link", "new file"]);
+            std::fs::remove_file(&file_name)?;
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_atomic_group_owned() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() {
+            if let Ok(user) = crate::target_user() {
+                let dir = std::env::temp_dir()
+                    .join(format!("jelly_uidmng_atomic_group_{}", std::process::id()));
+                std::fs::create_dir_all(&dir)?;
+                std::os::unix::fs::chown(&dir, Some(user.uid.as_raw()), Some(user.gid.as_raw()))?;
+
+                // user が所属していないグループのファイルも user 権限で置き換えられる
+                let file_name = dir.join("config");
+                std::fs::write(&file_name, b"old")?;
+                std::os::unix::fs::chown(&file_name, Some(user.uid.as_raw()), Some(5678))?;
+                write_atomic_user(&file_name, b"new")?;
+                assert_eq!(read(&file_name)?, b"new");
+                assert_eq!(metadata(&file_name)?.uid, user.uid);
+
+                // 所属しているグループは引き継がれる
+                std::os::unix::fs::chown(
+                    &file_name,
+                    Some(user.uid.as_raw()),
+                    Some(user.gid.as_raw()),
+                )?;
+                write_atomic_user(&file_name, b"newer")?;
+                assert_eq!(read(&file_name)?, b"newer");
+                assert_eq!(metadata(&file_name)?.gid, user.gid);
+
+                std::fs::remove_dir_all(&dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_atomic_concurrent() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!(
+            "jelly_uidmng_atomic_concurrent_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let file_name = dir.join("config");
+
+        // 同じファイルに並行して書いても一時ファイルが混ざらない
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let file_name = file_name.clone();
+                std::thread::spawn(move || -> Result<(), Error> {
+                    let data = vec![b'0' + i as u8; 4096];
+                    for _ in 0..20 {
+                        write_atomic(&file_name, &data)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        let data = read(&file_name)?;
+        assert_eq!(data.len(), 4096);
+        assert!(data.iter().all(|&byte| byte == data[0]));
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use crate::offset::operand;
+use crate::{elevator, helper, is_root, with_root, with_user, Elevator, Error, OpenOptions};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::result::Result;
+use std::sync::Arc;
+
+/// Writes `data` to an opened attribute file with a single `write(2)`.
+fn write_once(filename: &Path, mut file: File, data: &[u8]) -> Result<(), Error> {
+    // 分割して書くと受け付けない属性があるので、書ききれなくても繰り返さない
+    let written = loop {
+        match file.write(data) {
+            Ok(n) => break n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::io(filename, e)),
+        }
+    };
+    if written != data.len() {
+        return Err(Error::ShortWrite {
+            path: filename.to_path_buf(),
+            written,
+            len: data.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Writes to an existing attribute file such as in sysfs or configfs.
+///
+/// The file is opened write-only without creating or truncating it, and `data` is written
+/// with a single `write(2)`. Errors from the kernel are returned as `Error::Io` with the
+/// original errno, and a partial write is returned as `Error::ShortWrite`.
+pub fn write_attr(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    let file = File::options()
+        .write(true)
+        .open(filename)
+        .map_err(|e| Error::io(filename, e))?;
+    write_once(filename, file, data)
+}
+
+/// Writes to an existing attribute file through `elevator` (or the configured one).
+fn write_attr_elevated(
+    elevator: Option<&Arc<dyn Elevator>>,
+    filename: &Path,
+    data: &[u8],
+) -> Result<(), Error> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // offset.rs と同じく dd で1回の write(2) にまとめ、ファイルは作らない
+    let args = [
+        operand("of", filename),
+        operand("bs", data.len().to_string()),
+        OsString::from("count=1"),
+        OsString::from("iflag=fullblock"),
+        OsString::from("conv=notrunc,nocreat"),
+        OsString::from("status=none"),
+    ];
+    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+    elevator::run_checked(elevator, OsStr::new("dd"), &args, Some(data))?;
+    Ok(())
+}
+
+/// Writes to an existing attribute file using `sudo` permissions.
+///
+/// If the privileged helper is running, the file is opened by the helper and written in this
+/// process, so the errno is kept. Otherwise `data` is written with a single `write(2)` by
+/// `dd` through the elevator, and errors are returned as `Error::SudoFailed`.
+pub fn write_attr_sudo(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    // ヘルパーが動いていなければ起動せずに dd で書く
+    let mut options = OpenOptions::new();
+    options.write(true);
+    match helper::with_helper(|helper| helper.open(filename, &options)) {
+        Some(file) => write_once(filename, file?, data),
+        None => write_attr_elevated(None, filename, data),
+    }
+}
+
+/// Writes to an existing attribute file using user permissions.
+pub fn write_attr_user(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_user(|| write_attr(filename, data))
+}
+
+/// Writes to an existing attribute file using root permissions, or `sudo` if allowed.
+pub fn write_attr_root(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_root(
+        || write_attr(filename, data),
+        || write_attr_sudo(filename, data),
+    )
+}
+
+/// Writes to an existing attribute file and tries to use root permissions if the initial write fails.
+pub fn write_attr_try(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    let result = write_attr(filename, data);
+    if result.is_err() && !is_root() {
+        write_attr_root(filename, data)
+    } else {
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sudo;
+
+    #[test]
+    fn test_write_attr() -> Result<(), Box<dyn std::error::Error>> {
+        let file_name =
+            std::env::temp_dir().join(format!("test_write_attr_{}", std::process::id()));
+
+        // 存在しないファイルは作らない
+        match write_attr(&file_name, b"1") {
+            Err(Error::Io { source, .. }) => assert_eq!(source.kind(), io::ErrorKind::NotFound),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(!file_name.exists());
+
+        // 切り詰めずに先頭から書く
+        std::fs::write(&file_name, b"hello")?;
+        write_attr(&file_name, b"ab")?;
+        assert_eq!(std::fs::read(&file_name)?, b"abllo");
+        std::fs::remove_file(&file_name)?;
+
+        // カーネルの errno がそのまま分かる
+        match write_attr("/dev/full", b"1") {
+            Err(Error::Io { source, .. }) => {
+                assert_eq!(source.raw_os_error(), Some(nix::libc::ENOSPC))
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_write_attr_elevated() -> Result<(), Box<dyn std::error::Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_attr",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let file_name =
+            std::env::temp_dir().join(format!("test_write_attr_elevated_{}", std::process::id()));
+
+        // ヘルパーがなくても切り詰めずに先頭から書く
+        std::fs::write(&file_name, b"hello")?;
+        write_attr_elevated(Some(&elevator), &file_name, b"ab")?;
+        assert_eq!(std::fs::read(&file_name)?, b"abllo");
+        std::fs::remove_file(&file_name)?;
+
+        // 存在しないファイルは作らない
+        assert!(write_attr_elevated(Some(&elevator), &file_name, b"1").is_err());
+        assert!(!file_name.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use crate::environ;
+use crate::{
+    command_timeout, has_root, target_user, Error, PrivChild, PrivilegeLock, RootGuard, UserGuard,
+};
+use nix::errno::Errno;
+use nix::libc;
+use nix::unistd::getgrouplist;
+use std::ffi::{CString, OsStr};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output, Stdio};
+use std::result::Result;
+
+/// Linux capabilities that can be raised instead of becoming root.
+///
+/// Capabilities added to the kernel later may be added here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+#[non_exhaustive]
+pub enum Cap {
+    /// CAP_CHOWN: change the owner and group of any file.
+    Chown = 0,
+    /// CAP_DAC_OVERRIDE: bypass file read/write/execute permission checks.
+    DacOverride = 1,
+    /// CAP_DAC_READ_SEARCH: bypass file read and directory read/search permission checks.
+    DacReadSearch = 2,
+    /// CAP_FOWNER: bypass checks that require the file owner, such as `chmod`.
+    Fowner = 3,
+    /// CAP_FSETID: keep the set-user-ID and set-group-ID bits when a file is modified.
+    Fsetid = 4,
+    /// CAP_KILL: send signals to any process.
+    Kill = 5,
+    /// CAP_SETGID: change group IDs and supplementary groups.
+    Setgid = 6,
+    /// CAP_SETUID: change user IDs.
+    Setuid = 7,
+    /// CAP_SETPCAP: change the capability bounding set and securebits.
+    Setpcap = 8,
+    /// CAP_LINUX_IMMUTABLE: set the immutable and append-only file attributes.
+    LinuxImmutable = 9,
+    /// CAP_NET_BIND_SERVICE: bind sockets to ports below 1024.
+    NetBindService = 10,
+    /// CAP_NET_BROADCAST: broadcast and listen to multicast (unused by the kernel).
+    NetBroadcast = 11,
+    /// CAP_NET_ADMIN: configure network interfaces, routing and firewalls.
+    NetAdmin = 12,
+    /// CAP_NET_RAW: use raw and packet sockets.
+    NetRaw = 13,
+    /// CAP_IPC_LOCK: lock memory with `mlock`.
+    IpcLock = 14,
+    /// CAP_IPC_OWNER: bypass permission checks on System V IPC objects.
+    IpcOwner = 15,
+    /// CAP_SYS_MODULE: load and unload kernel modules.
+    SysModule = 16,
+    /// CAP_SYS_RAWIO: do raw I/O such as `iopl` and `/dev/mem`.
+    SysRawio = 17,
+    /// CAP_SYS_CHROOT: use `chroot`.
+    SysChroot = 18,
+    /// CAP_SYS_PTRACE: trace and inspect any process.
+    SysPtrace = 19,
+    /// CAP_SYS_PACCT: enable or disable process accounting.
+    SysPacct = 20,
+    /// CAP_SYS_ADMIN: a wide range of system administration, such as `mount`.
+    SysAdmin = 21,
+    /// CAP_SYS_BOOT: reboot and load a new kernel.
+    SysBoot = 22,
+    /// CAP_SYS_NICE: raise priorities and set real-time scheduling and CPU affinity.
+    SysNice = 23,
+    /// CAP_SYS_RESOURCE: override resource limits.
+    SysResource = 24,
+    /// CAP_SYS_TIME: set the system clock and the RTC.
+    SysTime = 25,
+    /// CAP_SYS_TTY_CONFIG: use `vhangup` and privileged terminal ioctls.
+    SysTtyConfig = 26,
+    /// CAP_MKNOD: create special files with `mknod`.
+    Mknod = 27,
+    /// CAP_LEASE: take leases on any file.
+    Lease = 28,
+    /// CAP_AUDIT_WRITE: write records to the kernel audit log.
+    AuditWrite = 29,
+    /// CAP_AUDIT_CONTROL: configure kernel auditing.
+    AuditControl = 30,
+    /// CAP_SETFCAP: set file capabilities.
+    Setfcap = 31,
+    /// CAP_MAC_OVERRIDE: override Mandatory Access Control.
+    MacOverride = 32,
+    /// CAP_MAC_ADMIN: configure Mandatory Access Control.
+    MacAdmin = 33,
+    /// CAP_SYSLOG: use privileged `syslog` operations.
+    Syslog = 34,
+    /// CAP_WAKE_ALARM: set timers that wake up the system.
+    WakeAlarm = 35,
+    /// CAP_BLOCK_SUSPEND: block system suspend.
+    BlockSuspend = 36,
+    /// CAP_AUDIT_READ: read the kernel audit log through multicast netlink.
+    AuditRead = 37,
+    /// CAP_PERFMON: use performance monitoring such as `perf_event_open`.
+    Perfmon = 38,
+    /// CAP_BPF: use privileged BPF operations.
+    Bpf = 39,
+    /// CAP_CHECKPOINT_RESTORE: use checkpoint/restore operations such as setting the next PID.
+    CheckpointRestore = 40,
+}
+
+impl Cap {
+    fn mask(caps: &[Cap]) -> u64 {
+        caps.iter().fold(0, |mask, &cap| mask | (1 << cap as u8))
+    }
+}
+
+// capget/capset の構造体 (linux/capability.h)
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Capability sets of the calling thread as 64-bit masks.
+#[derive(Debug, Clone, Copy)]
+struct CapState {
+    effective: u64,
+    permitted: u64,
+    inheritable: u64,
+}
+
+impl CapState {
+    /// Reads the capability sets of the calling thread.
+    fn get() -> Result<Self, Errno> {
+        let mut header = CapHeader {
+            version: CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+        let ret = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+        Errno::result(ret)?;
+        let join = |low: u32, high: u32| (high as u64) << 32 | low as u64;
+        Ok(Self {
+            effective: join(data[0].effective, data[1].effective),
+            permitted: join(data[0].permitted, data[1].permitted),
+            inheritable: join(data[0].inheritable, data[1].inheritable),
+        })
+    }
+
+    /// Sets the capability sets of the calling thread.
+    ///
+    /// This only makes a system call, so it can be used between `fork` and `exec`.
+    fn set(&self) -> Result<(), Errno> {
+        let mut header = CapHeader {
+            version: CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let split = |mask: u64| (mask as u32, (mask >> 32) as u32);
+        let (effective, permitted, inheritable) = (
+            split(self.effective),
+            split(self.permitted),
+            split(self.inheritable),
+        );
+        let data = [
+            CapData {
+                effective: effective.0,
+                permitted: permitted.0,
+                inheritable: inheritable.0,
+            },
+            CapData {
+                effective: effective.1,
+                permitted: permitted.1,
+                inheritable: inheritable.1,
+            },
+        ];
+        let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+        Errno::result(ret).map(drop)
+    }
+}
+
+/// Restores the capability sets of the calling thread on drop.
+struct CapGuard(CapState);
+
+impl Drop for CapGuard {
+    fn drop(&mut self) {
+        // drop (panic 中を含む) では失敗を返せないので無視する
+        let _ = self.0.set();
+    }
+}
+
+/// Runs `f` in user mode with only the given capabilities raised.
+///
+/// This is a finer-grained alternative to `change_root`: the effective user is the
+/// invoking user, and only `caps` are added to the effective set of the calling thread
+/// while `f` runs. The permitted set is kept so that the process can return to root
+/// afterwards.
+///
+/// The capabilities are raised on the calling thread only. The switch to the user applies
+/// to the whole process, so other threads run as the user without them while `f` runs,
+/// and `f` must not hand privileged work to other threads.
+pub fn with_capabilities<T>(caps: &[Cap], f: impl FnOnce() -> T) -> Result<T, Error> {
+    // root 権限を保有していないと capability は上げられない
+    if !has_root() {
+        return Err(Error::NoRootPermission);
+    }
+
+    // euid を user にすると effective set は空になり、permitted set は残る
+    let _lock = PrivilegeLock::acquire();
+    let _user = UserGuard::new()?;
+    let saved = CapState::get()?;
+    let mask = Cap::mask(caps);
+    if mask & !saved.permitted != 0 {
+        return Err(Error::Nix(Errno::EPERM));
+    }
+
+    // 必要な capability だけを effective set に上げ、終わったら戻す
+    let _caps = CapGuard(saved);
+    CapState {
+        effective: mask,
+        ..saved
+    }
+    .set()?;
+    Ok(f())
+}
+
+/// Executes a command as the invoking user with only the given capabilities.
+///
+/// The child drops to the invoking user completely, keeps only `caps` in its
+/// permitted set and raises them into the ambient set so that they survive `exec`.
+pub fn command_with_caps<I, S>(caps: &[Cap], program: S, args: I) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    // root 権限を保有していないと capability は渡せない
+    if !has_root() {
+        return Err(Error::NoRootPermission);
+    }
+
+    // fork 後はメモリ確保ができないので、ユーザーの情報は先に用意しておく
+    let user = target_user()?;
+    let groups = match user.name.as_deref().map(CString::new) {
+        Some(Ok(name)) => getgrouplist(&name, user.gid)?,
+        _ => vec![user.gid],
+    };
+    let groups: Vec<libc::gid_t> = groups.iter().map(|gid| gid.as_raw()).collect();
+    let uid = user.uid.as_raw();
+    let gid = user.gid.as_raw();
+    let mask = Cap::mask(caps);
+    let caps: Vec<Cap> = caps.to_vec();
+
+    let program = program.as_ref();
+    let mut command = Command::new(program);
+    command.args(args);
+    environ::prepare_user(&mut command);
+    unsafe {
+        command.pre_exec(move || {
+            // uid を変更しても permitted set が消えないようにする
+            Errno::result(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0))?;
+            Errno::result(libc::setgroups(groups.len(), groups.as_ptr()))?;
+            Errno::result(libc::setresgid(gid, gid, gid))?;
+            Errno::result(libc::setresuid(uid, uid, uid))?;
+
+            // 必要な capability だけを残して ambient set に上げる
+            CapState {
+                effective: mask,
+                permitted: mask,
+                inheritable: mask,
+            }
+            .set()?;
+            for cap in &caps {
+                Errno::result(libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_RAISE,
+                    *cap as libc::c_ulong,
+                    0,
+                    0,
+                ))?;
+            }
+            Ok(())
+        });
+    }
+
+    // 時間切れの場合にまとめて kill できるようにプロセスグループを分ける
+    let timeout = command_timeout();
+    if timeout.is_some() {
+        command.process_group(0);
+    }
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // 子プロセスが全ての capability を持った状態から始まるように root で fork する
+    let child = {
+        let _lock = PrivilegeLock::acquire();
+        let _root = RootGuard::new()?;
+        command.spawn().map_err(|e| Error::spawn(program, e))?
+    };
+    PrivChild::new(child, program, timeout.is_some()).wait_with_input(None::<&[u8]>, timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read, read_user, write};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_mask() {
+        // 32 番以降は capget/capset の2つ目の要素になる
+        let mask = Cap::mask(&[Cap::Chown, Cap::Setfcap, Cap::CheckpointRestore]);
+        assert_eq!(mask, 1 | 1 << 31 | 1 << 40);
+    }
+
+    #[test]
+    fn test_capabilities() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() && target_user().is_ok() {
+            // root だけが読めるファイルを作る
+            let file_name = format!("/tmp/test_capabilities_{}.txt", std::process::id());
+            {
+                let _root = RootGuard::new()?;
+                write(&file_name, b"secret")?;
+                std::fs::set_permissions(&file_name, std::fs::Permissions::from_mode(0o600))?;
+            }
+            assert!(read_user(&file_name).is_err());
+
+            // CAP_DAC_OVERRIDE だけで読める
+            let data = with_capabilities(&[Cap::DacOverride], || {
+                assert!(!crate::is_root());
+                read(&file_name)
+            })??;
+            assert_eq!(data, b"secret");
+            assert!(read_user(&file_name).is_err());
+
+            let output = command_with_caps(&[Cap::DacOverride], "cat", [file_name.as_str()])?;
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"secret");
+            let output = command_with_caps(&[], "cat", [file_name.as_str()])?;
+            assert!(!output.status.success());
+            let output = command_with_caps(&[], "id", ["-u"])?;
+            assert_eq!(
+                String::from_utf8(output.stdout)?.trim(),
+                target_user()?.uid.to_string()
+            );
+
+            std::fs::remove_file(&file_name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,731 @@
+use crate::command::Pipes;
+use crate::elevator;
+use crate::{has_root, with_root, Elevator, Error, Mode, PasswordPrompt, PrivCommand};
+use nix::errno::Errno;
+use nix::sys::signal::{self, killpg, Signal};
+use nix::unistd::Pid;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, ExitStatus, Output};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// root で動いているプロセスには user からシグナルを送れないので kill も elevator 経由で行う
+// 引数は "PID:PPID" か "-PGID:PPID" で、調べた時から PPID が変わっていれば送らない
+// (終了して PID が再利用された無関係のプロセスに送らないようにする)
+// (既に終了したプロセスがあっても残りには送る)
+const SIGNAL_SCRIPT: &str = r#"
+ppid() {
+    stat=$(cat "/proc/$1/stat" 2>/dev/null) || return 1
+    set -f
+    set -- ${stat##*") "}
+    echo "$2"
+}
+signal=$1
+shift
+for target in "$@"; do
+    pid=${target%:*}
+    [ "$(ppid "${pid#-}")" = "${target#*:}" ] && kill -s "$signal" -- "$pid" 2>/dev/null
+done
+:
+"#;
+
+// 時間切れで kill した後にパイプに残っている出力を読むために待つ時間
+const READ_GRACE: Duration = Duration::from_millis(100);
+
+/// A handle to a command started by `spawn_root` and friends or `PrivCommand::spawn`.
+///
+/// For a command started through `sudo`, the process of this handle is the elevator, and
+/// signals are sent with root permissions to the processes it started, through the same
+/// elevator that started it. The elevator never asks for a password to send a signal.
+pub struct PrivChild {
+    child: Child,
+    program: OsString,
+    elevator: Option<Arc<dyn Elevator>>,
+    group: bool,
+}
+
+impl fmt::Debug for PrivChild {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrivChild")
+            .field("child", &self.child)
+            .field("program", &self.program)
+            .field("elevated", &self.is_elevated())
+            .field("group", &self.group)
+            .finish()
+    }
+}
+
+impl PrivChild {
+    /// Wraps a child process started directly.
+    ///
+    /// `group` tells that the child leads its own process group.
+    pub(crate) fn new(child: Child, program: &OsStr, group: bool) -> Self {
+        Self {
+            child,
+            program: program.to_os_string(),
+            elevator: None,
+            group,
+        }
+    }
+
+    /// Wraps an elevator started by `elevator` with `prompt`.
+    ///
+    /// The elevator leads its own process group unless `prompt` reads the terminal.
+    pub(crate) fn elevated(
+        child: Child,
+        program: &OsStr,
+        elevator: &Arc<dyn Elevator>,
+        prompt: &PasswordPrompt,
+    ) -> Self {
+        Self {
+            child,
+            program: program.to_os_string(),
+            elevator: Some(elevator.clone()),
+            group: prompt.own_group(),
+        }
+    }
+
+    /// Returns the process ID, which is that of the elevator if started through `sudo`.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Checks if the command was started through `sudo` (or the configured elevator).
+    pub fn is_elevated(&self) -> bool {
+        self.elevator.is_some()
+    }
+
+    /// Takes the standard input if it is piped.
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
+    /// Takes the standard output if it is piped.
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    /// Takes the standard error if it is piped.
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.child.stderr.take()
+    }
+
+    /// Sends a signal to the command.
+    ///
+    /// If the command runs in its own process group, the signal is sent to the group.
+    /// If started through `sudo`, the signal is sent with `kill` run through the elevator that
+    /// started it to every process the elevator started, and to their process groups. The
+    /// elevator is run without asking for a password, so this fails with
+    /// `Error::PasswordRequired` if one is needed.
+    pub fn signal(&self, signal: Signal) -> Result<(), Error> {
+        let pid = Pid::from_raw(self.child.id() as i32);
+        match &self.elevator {
+            Some(elevator) => self.signal_elevated(elevator, signal, false),
+            None if self.group => killpg(pid, signal).map_err(Error::Nix),
+            None => signal::kill(pid, signal).map_err(Error::Nix),
+        }
+    }
+
+    /// Sends a signal to the processes started by the elevator through `elevator`.
+    ///
+    /// With `group`, the signal is also sent to the process group of the elevator, if it leads one.
+    fn signal_elevated(
+        &self,
+        elevator: &Arc<dyn Elevator>,
+        signal: Signal,
+        group: bool,
+    ) -> Result<(), Error> {
+        let pid = self.child.id();
+        let targets = descendants(pid);
+        if targets.is_empty() {
+            // パスワード入力中などでまだコマンドが起動していなければ elevator 自身に送る
+            return signal::kill(Pid::from_raw(pid as i32), signal).map_err(Error::Nix);
+        }
+
+        let mut args = vec![OsString::from(signal.as_str().trim_start_matches("SIG"))];
+        for (pid, ppid, leader) in targets {
+            args.push(OsString::from(format!("{}:{}", pid, ppid)));
+            if leader {
+                args.push(OsString::from(format!("-{}:{}", pid, ppid)));
+            }
+        }
+        if group && self.group {
+            // 子孫から外れたプロセスも elevator のプロセスグループにいれば届く
+            args.push(OsString::from(format!("-{}:{}", pid, std::process::id())));
+        }
+        let mut sh_args = vec![
+            OsString::from("-c"),
+            OsString::from(SIGNAL_SCRIPT),
+            OsString::from("sh"),
+        ];
+        sh_args.extend(args);
+        let sh_args: Vec<&OsStr> = sh_args.iter().map(OsString::as_os_str).collect();
+        // 時間切れの処理の途中でパスワードを尋ねないように、非対話で実行する
+        // (kill 自体には時間制限をかけない)
+        let output = elevator::run_elevated_timeout(
+            elevator,
+            &PasswordPrompt::NonInteractive,
+            OsStr::new("sh"),
+            &sh_args,
+            None,
+            None,
+        )?;
+        if !output.status.success() {
+            return Err(Error::sudo_failed(&output));
+        }
+        Ok(())
+    }
+
+    /// Kills the command with `SIGKILL`, together with its process group if it has one.
+    ///
+    /// For a command started through `sudo` in its own process group, the group is killed
+    /// directly if this process is allowed to, and through the elevator otherwise.
+    pub fn kill(&mut self) -> Result<(), Error> {
+        let result = match &self.elevator {
+            Some(elevator) => self.kill_elevated(elevator),
+            None if self.group => self.signal(Signal::SIGKILL),
+            None => return self.child.kill().map_err(|e| Error::io(&self.program, e)),
+        };
+        match result {
+            Err(Error::Nix(Errno::ESRCH)) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Kills the command started through `elevator` with its process group.
+    fn kill_elevated(&self, elevator: &Arc<dyn Elevator>) -> Result<(), Error> {
+        if self.group {
+            // elevator のプロセスグループに送れれば elevator を起動しなくて済む
+            let pid = Pid::from_raw(self.child.id() as i32);
+            match killpg(pid, Signal::SIGKILL) {
+                Err(Errno::EPERM) if has_root() => {
+                    return with_root(
+                        || killpg(pid, Signal::SIGKILL).map_err(Error::Nix),
+                        || Err(Error::NoRootPermission),
+                    );
+                }
+                Err(Errno::EPERM) => {}
+                result => return result.map_err(Error::Nix),
+            }
+        }
+        self.signal_elevated(elevator, Signal::SIGKILL, true)
+    }
+
+    /// Waits for the command to exit.
+    pub fn wait(&mut self) -> Result<ExitStatus, Error> {
+        self.child.wait().map_err(|e| Error::io(&self.program, e))
+    }
+
+    /// Returns the exit status if the command has exited, without waiting.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+        self.child
+            .try_wait()
+            .map_err(|e| Error::io(&self.program, e))
+    }
+
+    /// Waits for the command to exit for up to `timeout`, and returns `None` if it is still running.
+    fn wait_for(&mut self, timeout: Option<Duration>) -> Result<Option<ExitStatus>, Error> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.wait().map(Some),
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(10)));
+        }
+    }
+
+    /// Waits for the command to exit, killing it if `timeout` expires.
+    ///
+    /// The status after being killed is returned with `false`. If it could not be killed, the
+    /// error is returned together, and the command may still be running.
+    fn wait_or_kill(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<(ExitStatus, bool, Option<Error>), Error> {
+        if let Some(status) = self.wait_for(timeout)? {
+            return Ok((status, true, None));
+        }
+        match self.kill() {
+            Ok(()) => Ok((self.wait()?, false, None)),
+            // kill できなかった場合は終了を待たない
+            Err(e) => Ok((self.try_wait()?.unwrap_or_default(), false, Some(e))),
+        }
+    }
+
+    /// Waits for the command like `wait`, but kills it and returns `Error::TimedOut` if
+    /// `timeout` expires.
+    pub(crate) fn wait_timeout(&mut self, timeout: Option<Duration>) -> Result<ExitStatus, Error> {
+        match self.wait_or_kill(timeout)? {
+            (status, true, _) => Ok(status),
+            (status, false, kill_error) => Err(Error::TimedOut {
+                timeout: timeout.unwrap_or_default(),
+                output: Output {
+                    status,
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                },
+                kill_error: kill_error.map(Box::new),
+            }),
+        }
+    }
+
+    /// Waits for the command to exit and collects the rest of its piped output.
+    pub fn wait_with_output(self) -> Result<Output, Error> {
+        self.wait_with_input(None::<&[u8]>, None)
+    }
+
+    /// Copies `stdin` to the piped standard input, and waits for the command as `wait_with_output`.
+    ///
+    /// If `timeout` expires, the command is killed and `Error::TimedOut` is returned with
+    /// the output read until then. The output is not waited for after `timeout` either, in case
+    /// a process that was not killed keeps the pipes open.
+    pub(crate) fn wait_with_input(
+        mut self,
+        stdin: Option<impl Read + Send>,
+        timeout: Option<Duration>,
+    ) -> Result<Output, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let program = self.program.clone();
+        let with_name = |e| Error::io(&program, e);
+        let pipes = (
+            self.child.stdin.take(),
+            self.child.stdout.take(),
+            self.child.stderr.take(),
+        );
+
+        // 出力の読み出しと並行して書き込まないとパイプが詰まる事があるので別スレッドで読み書きする
+        // (読み出しのスレッドは時間切れの後に待たずに切り離せるようにスコープの外で動かす)
+        let stdout = pipes.1.map(Reader::spawn);
+        let stderr = pipes.2.map(Reader::spawn);
+        thread::scope(|scope| {
+            // 入力が無ければパイプを閉じて EOF にする
+            let writer = match (stdin, pipes.0) {
+                (Some(stdin), Some(pipe)) => Some(scope.spawn(move || copy_input(stdin, pipe))),
+                _ => None,
+            };
+
+            let (status, mut exited, mut kill_error) = self.wait_or_kill(timeout)?;
+            // kill した場合はパイプに残っている分だけ少し待って読む
+            let until = deadline.map(|deadline| match exited {
+                true => deadline,
+                false => Instant::now() + READ_GRACE,
+            });
+            let (stdout, stdout_done) = Reader::finish(stdout, until);
+            let (stderr, stderr_done) = Reader::finish(stderr, until);
+            if !(stdout_done && stderr_done) && exited {
+                // 終了したコマンドが起動したプロセスがパイプを開いたままにしている
+                kill_error = self.kill().err();
+                exited = false;
+            }
+            let output = Output {
+                status,
+                stdout,
+                stderr,
+            };
+            if let Some(Ok(Err(e))) = writer.map(|writer| writer.join()) {
+                return Err(with_name(e));
+            }
+            match timeout {
+                Some(timeout) if !exited => Err(Error::TimedOut {
+                    timeout,
+                    output,
+                    kill_error: kill_error.map(Box::new),
+                }),
+                _ => Ok(output),
+            }
+        })
+    }
+}
+
+/// Reads a pipe in its own thread, keeping what was read so far.
+struct Reader {
+    data: Arc<Mutex<Vec<u8>>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Reader {
+    fn spawn(pipe: impl Read + Send + 'static) -> Self {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let shared = data.clone();
+        let thread = thread::spawn(move || read_all(pipe, &shared));
+        Self { data, thread }
+    }
+
+    /// Waits for the end of the pipe until `deadline`, and returns what was read and whether
+    /// the end was reached.
+    ///
+    /// The thread is left running if `deadline` passes.
+    fn finish(reader: Option<Self>, deadline: Option<Instant>) -> (Vec<u8>, bool) {
+        let reader = match reader {
+            Some(reader) => reader,
+            None => return (Vec::new(), true),
+        };
+        let done = match deadline {
+            Some(deadline) => loop {
+                if reader.thread.is_finished() {
+                    break true;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    break false;
+                }
+                thread::sleep((deadline - now).min(Duration::from_millis(10)));
+            },
+            None => true,
+        };
+        if done {
+            let _ = reader.thread.join();
+        }
+        let data = mem::take(&mut *reader.data.lock().unwrap());
+        (data, done)
+    }
+}
+
+/// Reads the parent process ID and the process group ID of `pid` from `/proc`.
+pub(crate) fn proc_stat(pid: u32) -> Option<(u32, u32)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm には空白や括弧が含まれる事があるので最後の ')' の後ろを読む
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(1);
+    let ppid = fields.next()?.parse().ok()?;
+    let pgid = fields.next()?.parse().ok()?;
+    Some((ppid, pgid))
+}
+
+/// Returns the descendants of `pid` with their parents, and whether each leads its own process group.
+fn descendants(pid: u32) -> Vec<(u32, u32, bool)> {
+    // (pid, ppid, pgid) を /proc から集める
+    let mut processes = Vec::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let pid = match entry.file_name().to_str().map(str::parse::<u32>) {
+                Some(Ok(pid)) => pid,
+                _ => continue,
+            };
+            if let Some((ppid, pgid)) = proc_stat(pid) {
+                processes.push((pid, ppid, pgid));
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut parents = vec![pid];
+    while let Some(parent) = parents.pop() {
+        for &(pid, ppid, pgid) in &processes {
+            if ppid == parent {
+                result.push((pid, ppid, pid == pgid));
+                parents.push(pid);
+            }
+        }
+    }
+    result
+}
+
+/// Copies `input` to the standard input of a command until the end of `input`.
+///
+/// Only errors reading `input` are returned. If the command exits without reading
+/// everything, the write fails with `EPIPE`, which is judged by the exit status instead.
+fn copy_input(mut input: impl Read, mut pipe: impl Write) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if pipe.write_all(&buf[..len]).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads everything from a pipe into `data`, keeping what was read before an error.
+fn read_all(mut pipe: impl Read, data: &Mutex<Vec<u8>>) {
+    let mut buf = [0u8; 8192];
+    loop {
+        match pipe.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => data.lock().unwrap().extend_from_slice(&buf[..len]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Starts a command with piped output, as the mode says.
+fn spawn_with<I, S>(mode: Mode, program: S, args: I) -> Result<PrivChild, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    PrivCommand::new(program)
+        .args(args)
+        .mode(mode)
+        .spawn_child(Pipes::Output)
+}
+
+/// Starts a command without waiting for it to finish.
+///
+/// The standard output and error are piped and can be read from the returned handle.
+pub fn spawn<I, S>(program: S, args: I) -> Result<PrivChild, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    spawn_with(Mode::Current, program, args)
+}
+
+/// Starts a command with `sudo` (or the configured elevator).
+pub fn spawn_sudo<I, S>(program: S, args: I) -> Result<PrivChild, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    spawn_with(Mode::Sudo, program, args)
+}
+
+/// Starts a command in user mode.
+pub fn spawn_user<I, S>(program: S, args: I) -> Result<PrivChild, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    spawn_with(Mode::User, program, args)
+}
+
+/// Starts a command with root privileges, or `sudo` if allowed.
+pub fn spawn_root<I, S>(program: S, args: I) -> Result<PrivChild, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    spawn_with(Mode::Root, program, args)
+}
+
+/// Starts a command and tries to use root permissions if it fails to start.
+pub fn spawn_try<I, S>(program: S, args: I) -> Result<PrivChild, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    spawn_with(Mode::Try, program, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{has_root, Sudo};
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_spawn() -> Result<(), Box<dyn std::error::Error>> {
+        let mut child = spawn("sh", ["-c", "echo ready; exec sleep 10"])?;
+        let mut line = String::new();
+        BufReader::new(child.take_stdout().unwrap()).read_line(&mut line)?;
+        assert_eq!(line, "ready\n");
+        assert!(child.try_wait()?.is_none());
+
+        child.signal(Signal::SIGTERM)?;
+        assert_eq!(child.wait()?.signal(), Some(Signal::SIGTERM as i32));
+
+        let output = spawn("echo", ["hello"])?.wait_with_output()?;
+        assert_eq!(output.stdout, b"hello\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_spawn_elevated() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() {
+            // exec しない elevator では実際のコマンドは elevator の子プロセスになる
+            // (呼ばれた回数を記録して、kill にも同じ elevator が使われる事を確かめる)
+            let log =
+                std::env::temp_dir().join(format!("test_spawn_elevated_{}", std::process::id()));
+            let shim = crate::tests::write_shim(
+                "sudo_spawn",
+                &format!(
+                    "#!/bin/sh\necho run >> '{}'\nwhile [ \"${{1#-}}\" != \"$1\" ]; do\n  [ \"$1\" = -- ] && {{ shift; break; }}\n  shift\ndone\n\"$@\"\n",
+                    log.display()
+                ),
+            );
+            let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+
+            let mut child = PrivCommand::new("sh")
+                .args(["-c", "echo ready; exec sleep 10"])
+                .spawn_elevated(Some(&elevator), Pipes::Output)?;
+            assert!(child.is_elevated());
+            let mut line = String::new();
+            BufReader::new(child.take_stdout().unwrap()).read_line(&mut line)?;
+            assert_eq!(line, "ready\n");
+
+            // elevator ではなくコマンドにシグナルが届き、elevator も終了する
+            child.signal(Signal::SIGKILL)?;
+            let status = child.wait()?;
+            assert_eq!(status.code(), Some(128 + Signal::SIGKILL as i32));
+            assert_eq!(std::fs::read_to_string(&log)?, "run\nrun\n");
+            std::fs::remove_file(&log)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout() -> Result<(), Box<dyn std::error::Error>> {
+        // 時間切れの場合はコマンドが起動したプロセスもまとめて kill する
+        // (残っていると出力のパイプが閉じられずに待ち続けてしまう)
+        let start = Instant::now();
+        match PrivCommand::new("sh")
+            .args(["-c", "sleep 10 & echo started; wait"])
+            .timeout(Duration::from_millis(200))
+            .output()
+        {
+            Err(Error::TimedOut {
+                timeout,
+                output,
+                kill_error: None,
+            }) => {
+                assert_eq!(timeout, Duration::from_millis(200));
+                assert_eq!(output.stdout, b"started\n");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let result = PrivCommand::new("sleep")
+            .arg("10")
+            .timeout(Duration::from_millis(100))
+            .status();
+        assert!(matches!(result, Err(Error::TimedOut { .. })));
+
+        // kill できないプロセスが出力のパイプを開いたままでも時間切れで戻る
+        match PrivCommand::new("sh")
+            .args(["-c", "setsid sleep 3 & echo started"])
+            .timeout(Duration::from_millis(200))
+            .output()
+        {
+            Err(Error::TimedOut { output, .. }) => assert_eq!(output.stdout, b"started\n"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        if has_root() {
+            let shim = crate::tests::write_shim(
+                "sudo_timeout",
+                "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+            );
+            let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+            let result = PrivCommand::new("sleep")
+                .arg("10")
+                .timeout(Duration::from_millis(100))
+                .output_elevated(Some(&elevator));
+            assert!(matches!(result, Err(Error::TimedOut { .. })));
+
+            // パスワードを端末で聞かない場合は elevator のプロセスグループごと kill する
+            // (親が終了して子孫から外れたプロセスも残らない)
+            let shim = crate::tests::write_shim(
+                "sudo_timeout_group",
+                "#!/bin/sh\nwhile [ \"${1#-}\" != \"$1\" ]; do\n  [ \"$1\" = -- ] && { shift; break; }\n  shift\ndone\nexec \"$@\"\n",
+            );
+            let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+            let pid_file =
+                std::env::temp_dir().join(format!("test_timeout_group_{}", std::process::id()));
+            let script = r#"(sleep 10 & echo $! > "$1"); echo started; sleep 10"#;
+            let args = [
+                OsStr::new("-c"),
+                OsStr::new(script),
+                OsStr::new("sh"),
+                pid_file.as_os_str(),
+            ];
+            let result = elevator::run_elevated_timeout(
+                &elevator,
+                &PasswordPrompt::NonInteractive,
+                OsStr::new("sh"),
+                &args,
+                None,
+                Some(Duration::from_millis(300)),
+            );
+            assert!(matches!(result, Err(Error::TimedOut { .. })));
+            let pid = std::fs::read_to_string(&pid_file)?.trim().to_string();
+            std::fs::remove_file(&pid_file)?;
+            // init が回収しない環境もあるのでゾンビは終了したものとみなす
+            let alive = || {
+                let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+                let state = stat
+                    .rfind(')')
+                    .and_then(|i| stat[i + 1..].split_whitespace().next());
+                state.is_some_and(|state| state != "Z")
+            };
+            let killed = Instant::now();
+            while alive() && killed.elapsed() < Duration::from_secs(1) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(!alive());
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_signal_script() -> Result<(), Box<dyn std::error::Error>> {
+        // 親が変わっていれば (PID が再利用されていれば) シグナルを送らない
+        let mut child = std::process::Command::new("sleep").arg("10").spawn()?;
+        let run = |target: String| {
+            std::process::Command::new("sh")
+                .args(["-c", SIGNAL_SCRIPT, "sh", "KILL", &target])
+                .status()
+        };
+        assert!(run(format!("{}:1", child.id()))?.success());
+        thread::sleep(Duration::from_millis(100));
+        assert!(child.try_wait()?.is_none());
+
+        assert!(run(format!("{}:{}", child.id(), std::process::id()))?.success());
+        assert_eq!(child.wait()?.signal(), Some(Signal::SIGKILL as i32));
+        Ok(())
+    }
+
+    #[test]
+    fn test_kill_failed() -> Result<(), Box<dyn std::error::Error>> {
+        // kill の sudo がパスワードを求めても待たずに、それまでの出力と kill のエラーを返す
+        // (exec しない elevator なので kill は elevator 経由になる)
+        let shim = crate::tests::write_shim(
+            "sudo_kill_failed",
+            "#!/bin/sh\nif [ \"$1\" = -n ]; then\n  echo 'sudo: a password is required' >&2\n  exit 1\nfi\n[ \"$1\" = -- ] && shift\n\"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let start = Instant::now();
+        let args = [OsStr::new("-c"), OsStr::new("echo started; sleep 2")];
+        let result = elevator::run_elevated_timeout(
+            &elevator,
+            &PasswordPrompt::Terminal,
+            OsStr::new("sh"),
+            &args,
+            None,
+            Some(Duration::from_millis(200)),
+        );
+        match result {
+            Err(Error::TimedOut {
+                output,
+                kill_error: Some(kill_error),
+                ..
+            }) => {
+                assert_eq!(output.stdout, b"started\n");
+                assert!(matches!(*kill_error, Error::PasswordRequired));
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+}
@@ -0,0 +1,688 @@
+use crate::elevator::{self, password_prompt};
+use crate::environ;
+use crate::{is_root, Elevator, Error, Mode, PasswordPrompt, PrivChild};
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::result::Result;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+static COMMAND_TIMEOUT: RwLock<Option<Duration>> = RwLock::new(None);
+
+/// Sets the default timeout of commands.
+///
+/// This applies to the `command_*` functions, `command_with_caps`, `PrivCommand` without its
+/// own timeout, and the commands the `*_sudo` functions run through the elevator. A command
+/// that does not finish in time is killed with its process group, and `Error::TimedOut` is
+/// returned. Commands run by the privileged helper are limited in the same way.
+pub fn set_command_timeout(timeout: Option<Duration>) {
+    *COMMAND_TIMEOUT.write().unwrap() = timeout;
+}
+
+/// Returns the default timeout of commands.
+pub fn command_timeout() -> Option<Duration> {
+    *COMMAND_TIMEOUT.read().unwrap()
+}
+
+/// A builder for a command run with the permissions of a `Mode`.
+///
+/// This wraps `std::process::Command` and routes the command in the same way as the
+/// `command_*` functions. Through `sudo`, the environment and working directory are set
+/// with `env` and `sh` run as root.
+#[derive(Debug, Clone)]
+pub struct PrivCommand {
+    program: OsString,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, Option<OsString>)>,
+    env_clear: bool,
+    current_dir: Option<PathBuf>,
+    input: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    mode: Mode,
+}
+
+impl PrivCommand {
+    /// Creates a command for `program` that runs with the current permissions.
+    pub fn new(program: impl AsRef<OsStr>) -> Self {
+        Self {
+            program: program.as_ref().to_os_string(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            env_clear: false,
+            current_dir: None,
+            input: None,
+            timeout: None,
+            mode: Mode::Current,
+        }
+    }
+
+    /// Adds an argument.
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Adds multiple arguments.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// Sets an environment variable.
+    pub fn env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        self.envs.push((
+            key.as_ref().to_os_string(),
+            Some(value.as_ref().to_os_string()),
+        ));
+        self
+    }
+
+    /// Sets multiple environment variables.
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (key, value) in vars {
+            self.env(key, value);
+        }
+        self
+    }
+
+    /// Removes an environment variable.
+    pub fn env_remove(&mut self, key: impl AsRef<OsStr>) -> &mut Self {
+        self.envs.push((key.as_ref().to_os_string(), None));
+        self
+    }
+
+    /// Clears every environment variable, including those set before.
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.env_clear = true;
+        self.envs.clear();
+        self
+    }
+
+    /// Sets the working directory.
+    pub fn current_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets data written to the standard input of the command.
+    ///
+    /// Without this, the standard input is empty for `output` and inherited otherwise.
+    pub fn input(&mut self, data: impl Into<Vec<u8>>) -> &mut Self {
+        self.input = Some(data.into());
+        self
+    }
+
+    /// Sets how long `output` and `status` wait before killing the command.
+    ///
+    /// When it expires, the command is killed with its process group and `Error::TimedOut`
+    /// is returned. Without this, the timeout set by `set_command_timeout` is used.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the permissions the command runs with.
+    pub fn mode(&mut self, mode: Mode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Returns the timeout of this command, or the default one.
+    fn effective_timeout(&self) -> Option<Duration> {
+        self.timeout.or_else(command_timeout)
+    }
+
+    /// Builds the command run directly with the current permissions.
+    ///
+    /// Unless the mode is `Mode::Current`, the environment is prepared for the permissions
+    /// as `set_user_env` and `set_root_env` say, before the variables set on this builder.
+    ///
+    /// With a timeout, the command runs in its own process group so that it can be killed
+    /// together with the processes it started.
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        if self.effective_timeout().is_some() {
+            command.process_group(0);
+        }
+        if self.env_clear {
+            command.env_clear();
+        } else if self.mode != Mode::Current {
+            environ::prepare(&mut command);
+        }
+        for (key, value) in &self.envs {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        command
+    }
+
+    /// Returns the program and arguments run through an elevator.
+    ///
+    /// The elevator does not pass the environment and working directory of this process,
+    /// so they are set by `env` and `sh` run as root.
+    fn elevated_argv(&self) -> Vec<OsString> {
+        let mut argv = Vec::new();
+        if self.env_clear || !self.envs.is_empty() {
+            argv.push(OsString::from("env"));
+            if self.env_clear {
+                argv.push(OsString::from("-i"));
+            }
+            for (key, value) in &self.envs {
+                if value.is_none() {
+                    argv.push(OsString::from("-u"));
+                    argv.push(key.clone());
+                }
+            }
+            argv.push(OsString::from("--"));
+            for (key, value) in &self.envs {
+                if let Some(value) = value {
+                    let mut var = key.clone();
+                    var.push("=");
+                    var.push(value);
+                    argv.push(var);
+                }
+            }
+        }
+        if let Some(dir) = &self.current_dir {
+            // ディレクトリもシェルに埋め込まず位置パラメータで渡す
+            argv.push(OsString::from("sh"));
+            argv.push(OsString::from("-c"));
+            argv.push(OsString::from(r#"cd -- "$1" && shift && exec "$@""#));
+            argv.push(OsString::from("sh"));
+            argv.push(dir.clone().into_os_string());
+        }
+        argv.push(self.program.clone());
+        argv.extend(self.args.iter().cloned());
+        argv
+    }
+
+    /// Builds the command run through `elevator`, asking for a password as `prompt` says.
+    fn elevated_command(
+        &self,
+        elevator: &Arc<dyn Elevator>,
+        prompt: &PasswordPrompt,
+    ) -> Result<Command, Error> {
+        let argv = self.elevated_argv();
+        let args: Vec<&OsStr> = argv[1..].iter().map(OsString::as_os_str).collect();
+        let mut command = elevator::elevated_command(elevator, prompt, &argv[0], &args)?;
+        // 時間切れの時にまとめて kill できるようにプロセスグループを分ける
+        if prompt.own_group() {
+            command.process_group(0);
+        }
+        Ok(command)
+    }
+
+    /// Spawns `command` with its standard streams connected as `pipes` says.
+    ///
+    /// The input set by `input` is written from another thread.
+    fn spawn_command(&self, mut command: Command, pipes: Pipes) -> Result<Child, Error> {
+        if self.input.is_some() || pipes == Pipes::All {
+            command.stdin(Stdio::piped());
+        } else if pipes == Pipes::Output {
+            command.stdin(Stdio::null());
+        }
+        if pipes != Pipes::Inherit {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| Error::spawn(command.get_program(), e))?;
+        if let Some(data) = self.input.clone() {
+            if let Some(mut pipe) = child.stdin.take() {
+                thread::spawn(move || {
+                    let _ = pipe.write_all(&data);
+                });
+            }
+        }
+        Ok(child)
+    }
+
+    /// Starts the command as the mode says.
+    pub(crate) fn spawn_child(&self, pipes: Pipes) -> Result<PrivChild, Error> {
+        self.mode.run(
+            || {
+                let child = self.spawn_command(self.command(), pipes)?;
+                Ok(self.direct_child(child))
+            },
+            || self.spawn_elevated(None, pipes),
+        )
+    }
+
+    /// Starts the command through `elevator` (or the configured one).
+    pub(crate) fn spawn_elevated(
+        &self,
+        elevator: Option<&Arc<dyn Elevator>>,
+        pipes: Pipes,
+    ) -> Result<PrivChild, Error> {
+        let elevator = elevator.cloned().unwrap_or_else(elevator::elevator);
+        let prompt = password_prompt();
+        let child = self.spawn_command(self.elevated_command(&elevator, &prompt)?, pipes)?;
+        Ok(PrivChild::elevated(
+            child,
+            &self.program,
+            &elevator,
+            &prompt,
+        ))
+    }
+
+    /// Wraps a child started directly by `command`.
+    fn direct_child(&self, child: Child) -> PrivChild {
+        let group = self.effective_timeout().is_some();
+        PrivChild::new(child, &self.program, group)
+    }
+
+    /// Runs the command with the current permissions and captures its output.
+    fn output_direct(&self) -> Result<Output, Error> {
+        let child = self.spawn_command(self.command(), Pipes::Output)?;
+        self.direct_child(child)
+            .wait_with_input(None::<&[u8]>, self.effective_timeout())
+    }
+
+    /// Runs the command through `elevator` (or the configured one) and captures its output.
+    pub(crate) fn output_elevated(
+        &self,
+        elevator: Option<&Arc<dyn Elevator>>,
+    ) -> Result<Output, Error> {
+        let argv = self.elevated_argv();
+        let args: Vec<&OsStr> = argv[1..].iter().map(OsString::as_os_str).collect();
+        let input = self.input.as_deref();
+        match (elevator, self.effective_timeout()) {
+            // helper が起動していれば helper で実行する
+            (None, timeout) => elevator::run_elevated(&argv[0], &args, input, timeout),
+            (Some(elevator), timeout) => elevator::run_elevated_timeout(
+                elevator,
+                &password_prompt(),
+                &argv[0],
+                &args,
+                input,
+                timeout,
+            ),
+        }
+    }
+
+    /// Runs the command and waits for it to finish, with its output inherited.
+    fn status_direct(&self) -> Result<ExitStatus, Error> {
+        let child = self.spawn_command(self.command(), Pipes::Inherit)?;
+        self.direct_child(child)
+            .wait_timeout(self.effective_timeout())
+    }
+
+    /// Runs the command through the configured elevator and waits for it to finish.
+    fn status_elevated(&self) -> Result<ExitStatus, Error> {
+        self.spawn_elevated(None, Pipes::Inherit)?
+            .wait_timeout(self.effective_timeout())
+    }
+
+    /// Runs the command and captures its output.
+    ///
+    /// With `Mode::Try`, the command is run again with root permissions if it fails to start
+    /// or exits with a failure status.
+    pub fn output(&self) -> Result<Output, Error> {
+        self.run(
+            || self.output_direct(),
+            || self.output_elevated(None),
+            |output| output.status.success(),
+        )
+    }
+
+    /// Runs the command with its output inherited and returns the exit status.
+    ///
+    /// With `Mode::Try`, the command is run again with root permissions if it fails to start
+    /// or exits with a failure status.
+    pub fn status(&self) -> Result<ExitStatus, Error> {
+        self.run(
+            || self.status_direct(),
+            || self.status_elevated(),
+            ExitStatus::success,
+        )
+    }
+
+    /// Runs the command reading its standard input from `reader`, and captures its output.
+    ///
+    /// The input set by `input` is ignored. With `Mode::Try`, the command is run again with
+    /// root permissions only if it fails to start, because `reader` cannot be read twice.
+    pub fn output_from(&self, reader: impl Read + Send) -> Result<Output, Error> {
+        let mut command = self.clone();
+        command.input = None;
+        command
+            .spawn_child(Pipes::All)?
+            .wait_with_input(Some(reader), self.effective_timeout())
+    }
+
+    /// Starts the command with its output inherited, without waiting for it to finish.
+    pub fn spawn(&self) -> Result<PrivChild, Error> {
+        self.spawn_child(Pipes::Inherit)
+    }
+
+    /// Runs `f` directly or `sudo` through an elevator as the mode says.
+    fn run<T>(
+        &self,
+        f: impl Fn() -> Result<T, Error>,
+        sudo: impl Fn() -> Result<T, Error>,
+        success: impl Fn(&T) -> bool,
+    ) -> Result<T, Error> {
+        if self.mode != Mode::Try {
+            return self.mode.run(f, sudo);
+        }
+
+        // command_try と同じく、終了ステータスが失敗の場合も root で再実行する
+        let result = f();
+        if result.as_ref().is_ok_and(&success) || is_root() {
+            result
+        } else {
+            Mode::Root.run(f, sudo)
+        }
+    }
+}
+
+/// Runs a command as the mode says, writing `stdin` to its standard input.
+fn command_with<I, S>(mode: Mode, program: S, args: I, stdin: &[u8]) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    PrivCommand::new(program)
+        .args(args)
+        .input(stdin)
+        .mode(mode)
+        .output()
+}
+
+/// Executes a command, writing `stdin` to its standard input.
+pub fn command_with_input<I, S>(program: S, args: I, stdin: &[u8]) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_with(Mode::Current, program, args, stdin)
+}
+
+/// Executes a command with `sudo` (or the configured elevator), writing `stdin` to its standard input.
+pub fn command_sudo_with_input<I, S>(program: S, args: I, stdin: &[u8]) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_with(Mode::Sudo, program, args, stdin)
+}
+
+/// Executes a command in user mode, writing `stdin` to its standard input.
+pub fn command_user_with_input<I, S>(program: S, args: I, stdin: &[u8]) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_with(Mode::User, program, args, stdin)
+}
+
+/// Executes a command with root privileges, writing `stdin` to its standard input.
+pub fn command_root_with_input<I, S>(program: S, args: I, stdin: &[u8]) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_with(Mode::Root, program, args, stdin)
+}
+
+/// Executes a command writing `stdin` to its standard input, and tries to use root
+/// permissions if the initial execution fails.
+pub fn command_try_with_input<I, S>(program: S, args: I, stdin: &[u8]) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_with(Mode::Try, program, args, stdin)
+}
+
+/// Runs a command as the mode says, streaming its standard input from `reader`.
+fn command_from<I, S>(
+    mode: Mode,
+    program: S,
+    args: I,
+    reader: impl Read + Send,
+) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    PrivCommand::new(program)
+        .args(args)
+        .mode(mode)
+        .output_from(reader)
+}
+
+/// Executes a command, streaming its standard input from `reader`.
+pub fn command_with_reader<I, S>(
+    program: S,
+    args: I,
+    reader: impl Read + Send,
+) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_from(Mode::Current, program, args, reader)
+}
+
+/// Executes a command with `sudo` (or the configured elevator), streaming its standard input from `reader`.
+///
+/// Unlike `command_sudo_with_input`, the command is never run by the privileged helper.
+pub fn command_sudo_with_reader<I, S>(
+    program: S,
+    args: I,
+    reader: impl Read + Send,
+) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_from(Mode::Sudo, program, args, reader)
+}
+
+/// Executes a command in user mode, streaming its standard input from `reader`.
+pub fn command_user_with_reader<I, S>(
+    program: S,
+    args: I,
+    reader: impl Read + Send,
+) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_from(Mode::User, program, args, reader)
+}
+
+/// Executes a command with root privileges, streaming its standard input from `reader`.
+pub fn command_root_with_reader<I, S>(
+    program: S,
+    args: I,
+    reader: impl Read + Send,
+) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_from(Mode::Root, program, args, reader)
+}
+
+/// Executes a command streaming its standard input from `reader`, and tries to use root
+/// permissions if the command fails to start.
+pub fn command_try_with_reader<I, S>(
+    program: S,
+    args: I,
+    reader: impl Read + Send,
+) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command_from(Mode::Try, program, args, reader)
+}
+
+/// How the standard streams of a started command are connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pipes {
+    /// Inherited from this process.
+    Inherit,
+    /// The standard output and error are piped, and the standard input is empty.
+    Output,
+    /// The standard input, output and error are all piped.
+    All,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{has_root, Sudo};
+    use std::io;
+    use std::time::Instant;
+
+    #[test]
+    fn test_priv_command() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir();
+        let output = PrivCommand::new("sh")
+            .args(["-c", r#"pwd; echo "$FOO,${HOME-unset}"; cat"#])
+            .env_clear()
+            .env("FOO", "foo bar")
+            .current_dir(&dir)
+            .input("input")
+            .output()?;
+        let expected = format!("{}\nfoo bar,unset\ninput", dir.display());
+        assert_eq!(String::from_utf8(output.stdout)?, expected);
+
+        let status = PrivCommand::new("false").status()?;
+        assert!(!status.success());
+        let mut child = PrivCommand::new("true").spawn()?;
+        assert!(!child.is_elevated());
+        assert!(child.wait()?.success());
+
+        // 時間切れの場合はそれまでの出力を返す
+        let start = Instant::now();
+        match PrivCommand::new("sh")
+            .args(["-c", "echo started; exec sleep 10"])
+            .timeout(Duration::from_millis(200))
+            .output()
+        {
+            Err(Error::TimedOut { output, .. }) => assert_eq!(output.stdout, b"started\n"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // 起動できない場合はファイルのエラーと区別できる
+        match PrivCommand::new("jelly_uidmng_no_such_program").output() {
+            Err(Error::Spawn { program, source }) => {
+                assert_eq!(program, "jelly_uidmng_no_such_program");
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let elevator: Arc<dyn Elevator> =
+            Arc::new(Sudo::with_program("/jelly_uidmng_no_such_sudo"));
+        match PrivCommand::new("true").output_elevated(Some(&elevator)) {
+            Err(Error::Spawn { program, .. }) => assert_eq!(program, "/jelly_uidmng_no_such_sudo"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_priv_command_elevated() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() {
+            let shim = crate::tests::write_shim(
+                "sudo_priv_command",
+                "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+            );
+            let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+
+            // sudo 経由でも環境変数と作業ディレクトリが渡る
+            let dir = std::env::temp_dir().join("dir with 'quote'");
+            std::fs::create_dir_all(&dir)?;
+            let output = PrivCommand::new("sh")
+                .args(["-c", r#"pwd; echo "$FOO,${BAR-unset}""#])
+                .env("FOO", "$(id)")
+                .env_remove("BAR")
+                .current_dir(&dir)
+                .output_elevated(Some(&elevator))?;
+            let expected = format!("{}\n$(id),unset\n", dir.display());
+            assert_eq!(String::from_utf8(output.stdout)?, expected);
+            std::fs::remove_dir(&dir)?;
+
+            let output = PrivCommand::new("cat")
+                .input("input")
+                .output_elevated(Some(&elevator))?;
+            assert_eq!(output.stdout, b"input");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_input() -> Result<(), Box<dyn std::error::Error>> {
+        let output = command_with_input("cat", [], b"input")?;
+        assert_eq!(output.stdout, b"input");
+
+        // 出力を読みながら大きな入力を流し込んでも詰まらない
+        let data = io::repeat(b'x').take(1 << 20);
+        let output = command_with_reader("cat", [], data)?;
+        assert_eq!(output.stdout.len(), 1 << 20);
+
+        let output = command_with_reader("wc", ["-c"], b"12345".as_slice())?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "5");
+
+        if has_root() {
+            let shim = crate::tests::write_shim(
+                "sudo_command_input",
+                "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+            );
+            let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+            let output = PrivCommand::new("cat")
+                .spawn_elevated(Some(&elevator), Pipes::All)?
+                .wait_with_input(Some(io::repeat(b'y').take(1 << 20)), None)?;
+            assert_eq!(output.stdout.len(), 1 << 20);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_priv_command_mode() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() && crate::target_user().is_ok() {
+            let uid = |mode| -> Result<String, Error> {
+                let output = PrivCommand::new("id").arg("-u").mode(mode).output()?;
+                Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+            };
+            assert_eq!(uid(Mode::Root)?, "0");
+            assert_eq!(uid(Mode::User)?, crate::target_user()?.uid.to_string());
+
+            // user で実行するコマンドには user の HOME を渡す
+            let user = crate::target_user()?;
+            if let Some(passwd) = nix::unistd::User::from_uid(user.uid)? {
+                let output = PrivCommand::new("sh")
+                    .args(["-c", "echo $HOME"])
+                    .mode(Mode::User)
+                    .output()?;
+                let home = String::from_utf8(output.stdout)?;
+                assert_eq!(home.trim(), passwd.dir.to_string_lossy());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,787 @@
+use crate::{command, helper, Error, PrivChild};
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::result::Result;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// A program that runs commands with root privileges, such as `sudo`.
+pub trait Elevator: Send + Sync {
+    /// Returns the name of the backend.
+    fn name(&self) -> &str;
+
+    /// Builds a command that runs `program` with `args` as root.
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command;
+
+    /// Builds a command like `command` that fails instead of asking for a password.
+    ///
+    /// Returns `None` if the backend has no non-interactive mode.
+    fn non_interactive_command(&self, _program: &OsStr, _args: &[&OsStr]) -> Option<Command> {
+        None
+    }
+
+    /// Builds a command like `command` that asks for the password with `askpass`.
+    ///
+    /// Returns `None` if the backend does not support askpass programs.
+    fn askpass_command(
+        &self,
+        _askpass: &Path,
+        _program: &OsStr,
+        _args: &[&OsStr],
+    ) -> Option<Command> {
+        None
+    }
+
+    /// Builds a command that authenticates with a password read from stdin.
+    ///
+    /// Later commands from `non_interactive_command` must then succeed without a password.
+    /// Returns `None` if the backend cannot read the password from stdin.
+    fn validate_command(&self) -> Option<Command> {
+        None
+    }
+
+    /// Builds a command like `command` that reads the password from the first line of stdin,
+    /// even if credentials are cached, and passes the rest of stdin to `program`.
+    ///
+    /// If no password is needed at all, the command must not read stdin.
+    /// Returns `None` if the backend cannot read the password from stdin.
+    fn password_command(&self, _program: &OsStr, _args: &[&OsStr]) -> Option<Command> {
+        None
+    }
+
+    /// Checks whether the stderr of a failed non-interactive command says a password is required.
+    fn password_required(&self, _stderr: &[u8]) -> bool {
+        false
+    }
+}
+
+/// Runs commands with `sudo`.
+#[derive(Debug, Clone)]
+pub struct Sudo {
+    program: PathBuf,
+}
+
+impl Sudo {
+    /// Creates a backend that uses `sudo` from `PATH`.
+    pub fn new() -> Self {
+        Self::with_program("sudo")
+    }
+
+    /// Creates a backend that uses the given `sudo` executable.
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl Default for Sudo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Elevator for Sudo {
+    fn name(&self) -> &str {
+        "sudo"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        let mut command = Command::new(&self.program);
+        command.arg("--").arg(program).args(args);
+        command
+    }
+
+    fn non_interactive_command(&self, program: &OsStr, args: &[&OsStr]) -> Option<Command> {
+        let mut command = Command::new(&self.program);
+        command.arg("-n").arg("--").arg(program).args(args);
+        Some(command)
+    }
+
+    fn askpass_command(&self, askpass: &Path, program: &OsStr, args: &[&OsStr]) -> Option<Command> {
+        let mut command = Command::new(&self.program);
+        command
+            .env("SUDO_ASKPASS", askpass)
+            .arg("-A")
+            .arg("--")
+            .arg(program)
+            .args(args);
+        Some(command)
+    }
+
+    fn validate_command(&self) -> Option<Command> {
+        // プロンプトを出さずに標準入力からパスワードを読んで認証だけ行う
+        let mut command = Command::new(&self.program);
+        command.arg("-S").arg("-p").arg("").arg("-v");
+        Some(command)
+    }
+
+    fn password_command(&self, program: &OsStr, args: &[&OsStr]) -> Option<Command> {
+        // キャッシュがあってもパスワードを読むように -k を付ける
+        // (sudo はパスワードを1文字ずつ読むので、残りはコマンドの入力になる)
+        let mut command = Command::new(&self.program);
+        command
+            .arg("-k")
+            .arg("-S")
+            .arg("-p")
+            .arg("")
+            .arg("--")
+            .arg(program)
+            .args(args);
+        Some(command)
+    }
+
+    fn password_required(&self, stderr: &[u8]) -> bool {
+        // -S で渡したパスワードが違った場合も含める
+        contains(stderr, b"password is required")
+            || contains(stderr, b"incorrect password attempt")
+            || contains(stderr, b"no password was provided")
+    }
+}
+
+/// Runs commands with `doas`.
+#[derive(Debug, Clone)]
+pub struct Doas {
+    program: PathBuf,
+}
+
+impl Doas {
+    /// Creates a backend that uses `doas` from `PATH`.
+    pub fn new() -> Self {
+        Self::with_program("doas")
+    }
+
+    /// Creates a backend that uses the given `doas` executable.
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl Default for Doas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Elevator for Doas {
+    fn name(&self) -> &str {
+        "doas"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        let mut command = Command::new(&self.program);
+        command.arg("--").arg(program).args(args);
+        command
+    }
+
+    fn non_interactive_command(&self, program: &OsStr, args: &[&OsStr]) -> Option<Command> {
+        let mut command = Command::new(&self.program);
+        command.arg("-n").arg("--").arg(program).args(args);
+        Some(command)
+    }
+
+    fn password_required(&self, stderr: &[u8]) -> bool {
+        contains(stderr, b"Authentication required") || contains(stderr, b"Authorization required")
+    }
+}
+
+/// Runs commands with `pkexec`.
+#[derive(Debug, Clone)]
+pub struct Pkexec {
+    program: PathBuf,
+}
+
+impl Pkexec {
+    /// Creates a backend that uses `pkexec` from `PATH`.
+    pub fn new() -> Self {
+        Self::with_program("pkexec")
+    }
+
+    /// Creates a backend that uses the given `pkexec` executable.
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl Default for Pkexec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Elevator for Pkexec {
+    fn name(&self) -> &str {
+        "pkexec"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        let mut command = Command::new(&self.program);
+        command.arg(program).args(args);
+        command
+    }
+}
+
+/// Runs commands with `su`.
+#[derive(Debug, Clone)]
+pub struct Su {
+    program: PathBuf,
+}
+
+impl Su {
+    /// Creates a backend that uses `su` from `PATH`.
+    pub fn new() -> Self {
+        Self::with_program("su")
+    }
+
+    /// Creates a backend that uses the given `su` executable.
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl Default for Su {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Elevator for Su {
+    fn name(&self) -> &str {
+        "su"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        // su はコマンドを文字列で受け取るので、プログラムと引数はシェルの位置パラメータで渡す
+        // (su は引数を並べ替えてオプションを探すので -- で止める)
+        let mut command = Command::new(&self.program);
+        command
+            .arg("root")
+            .arg("-c")
+            .arg(r#"exec "$0" "$@""#)
+            .arg("--")
+            .arg(program)
+            .args(args);
+        command
+    }
+}
+
+/// Built-in elevators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElevatorKind {
+    /// Uses the first of `sudo`, `doas`, `pkexec` and `su` found in `PATH`.
+    Auto,
+    /// Uses `sudo`.
+    Sudo,
+    /// Uses `doas`.
+    Doas,
+    /// Uses `pkexec`.
+    Pkexec,
+    /// Uses `su`.
+    Su,
+}
+
+/// How the elevator asks for a password.
+#[derive(Clone, Default)]
+pub enum PasswordPrompt {
+    /// Lets the elevator prompt on the terminal.
+    #[default]
+    Terminal,
+    /// Never prompts, and fails with `Error::PasswordRequired` if a password is needed.
+    NonInteractive,
+    /// Asks for the password with an askpass program (`sudo -A`).
+    Askpass(PathBuf),
+    /// Gets the password from a callback and feeds it to the elevator through stdin (`sudo -S`).
+    ///
+    /// The password buffer is zeroed after use. For commands whose output is captured, the
+    /// password is passed in the same invocation as the command, and the callback is called for
+    /// each command that needs a password. Commands started with inherited input (such as
+    /// `spawn_sudo` and `PrivCommand::status`) and the helper authenticate first (`sudo -v`) and
+    /// rely on the credentials cached by sudo, so they fail if sudo does not cache them.
+    Callback(Arc<dyn Fn() -> Option<Vec<u8>> + Send + Sync>),
+}
+
+impl PasswordPrompt {
+    /// Creates a `Callback` prompt from a function.
+    pub fn callback(f: impl Fn() -> Option<Vec<u8>> + Send + Sync + 'static) -> Self {
+        PasswordPrompt::Callback(Arc::new(f))
+    }
+
+    /// Checks if an elevator started with this prompt can run in its own process group.
+    ///
+    /// With `Terminal`, the elevator is left in the foreground group to read the password.
+    pub(crate) fn own_group(&self) -> bool {
+        !matches!(self, PasswordPrompt::Terminal)
+    }
+}
+
+impl fmt::Debug for PasswordPrompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordPrompt::Terminal => write!(f, "Terminal"),
+            PasswordPrompt::NonInteractive => write!(f, "NonInteractive"),
+            PasswordPrompt::Askpass(path) => f.debug_tuple("Askpass").field(path).finish(),
+            PasswordPrompt::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+static ELEVATOR: RwLock<Option<Arc<dyn Elevator>>> = RwLock::new(None);
+static PASSWORD_PROMPT: RwLock<PasswordPrompt> = RwLock::new(PasswordPrompt::Terminal);
+
+/// Sets how the elevator asks for a password.
+pub fn set_password_prompt(prompt: PasswordPrompt) {
+    *PASSWORD_PROMPT.write().unwrap() = prompt;
+}
+
+/// Returns how the elevator asks for a password.
+pub fn password_prompt() -> PasswordPrompt {
+    PASSWORD_PROMPT.read().unwrap().clone()
+}
+
+/// Sets the elevator used by the `*_sudo` functions.
+pub fn set_elevator(elevator: Arc<dyn Elevator>) {
+    *ELEVATOR.write().unwrap() = Some(elevator);
+}
+
+/// Selects one of the built-in elevators.
+pub fn set_elevator_kind(kind: ElevatorKind) {
+    let elevator: Arc<dyn Elevator> = match kind {
+        ElevatorKind::Auto => detect_elevator(),
+        ElevatorKind::Sudo => Arc::new(Sudo::new()),
+        ElevatorKind::Doas => Arc::new(Doas::new()),
+        ElevatorKind::Pkexec => Arc::new(Pkexec::new()),
+        ElevatorKind::Su => Arc::new(Su::new()),
+    };
+    set_elevator(elevator);
+}
+
+/// Returns the elevator used by the `*_sudo` functions.
+///
+/// If none has been set, the first one found in `PATH` is chosen.
+pub fn elevator() -> Arc<dyn Elevator> {
+    if let Some(elevator) = ELEVATOR.read().unwrap().as_ref() {
+        return elevator.clone();
+    }
+    ELEVATOR
+        .write()
+        .unwrap()
+        .get_or_insert_with(detect_elevator)
+        .clone()
+}
+
+/// Runs `program` with `args` as root through the configured elevator and captures its output.
+///
+/// `stdin` is written to the standard input of the command, and the command is killed if it
+/// does not finish within `timeout`.
+/// If the privileged helper is running and `set_helper_commands` is enabled, the command is
+/// run by the helper instead.
+pub(crate) fn run_elevated(
+    program: &OsStr,
+    args: &[&OsStr],
+    stdin: Option<&[u8]>,
+    timeout: Option<Duration>,
+) -> Result<Output, Error> {
+    // helper が起動していて、コマンドの実行が許可されていれば helper で実行する
+    if helper::helper_commands() {
+        if let Some(result) =
+            helper::with_helper(|helper| helper.command(program, args, stdin, timeout))
+        {
+            return result;
+        }
+    }
+    run_elevated_timeout(
+        &elevator(),
+        &password_prompt(),
+        program,
+        args,
+        stdin,
+        timeout,
+    )
+}
+
+/// Runs a shell script as root, passing `args` as its positional parameters.
+///
+/// The configured elevator (or the helper) is used if `elevator` is `None`.
+/// Returns the output if the script exits successfully.
+pub(crate) fn run_script(
+    elevator: Option<&Arc<dyn Elevator>>,
+    script: &str,
+    args: &[&OsStr],
+    stdin: Option<&[u8]>,
+) -> Result<Output, Error> {
+    // 値はスクリプトに埋め込まず "$1" 以降で参照させる
+    let mut sh_args = vec![OsStr::new("-c"), OsStr::new(script), OsStr::new("sh")];
+    sh_args.extend_from_slice(args);
+    run_checked(elevator, OsStr::new("sh"), &sh_args, stdin)
+}
+
+/// Runs `program` with `args` as root and returns the output if it exits successfully.
+///
+/// The configured elevator (or the helper) is used if `elevator` is `None`.
+pub(crate) fn run_checked(
+    elevator: Option<&Arc<dyn Elevator>>,
+    program: &OsStr,
+    args: &[&OsStr],
+    stdin: Option<&[u8]>,
+) -> Result<Output, Error> {
+    let output = match elevator {
+        Some(elevator) => run_elevated_with(elevator, &password_prompt(), program, args, stdin)?,
+        None => run_elevated(program, args, stdin, command::command_timeout())?,
+    };
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(Error::sudo_failed(&output))
+    }
+}
+
+/// Runs `program` with `args` as root through `elevator` and captures its output.
+pub(crate) fn run_elevated_with(
+    elevator: &Arc<dyn Elevator>,
+    prompt: &PasswordPrompt,
+    program: &OsStr,
+    args: &[&OsStr],
+    stdin: Option<&[u8]>,
+) -> Result<Output, Error> {
+    run_elevated_timeout(
+        elevator,
+        prompt,
+        program,
+        args,
+        stdin,
+        command::command_timeout(),
+    )
+}
+
+/// Like `run_elevated_with`, but kills the command if it does not finish within `timeout`.
+pub(crate) fn run_elevated_timeout(
+    elevator: &Arc<dyn Elevator>,
+    prompt: &PasswordPrompt,
+    program: &OsStr,
+    args: &[&OsStr],
+    stdin: Option<&[u8]>,
+    timeout: Option<Duration>,
+) -> Result<Output, Error> {
+    let output = match prompt {
+        PasswordPrompt::Callback(callback) => {
+            match run_with_password(elevator, prompt, &**callback, program, args, stdin, timeout)? {
+                Some(output) => output,
+                None => {
+                    let mut command = elevated_command(elevator, prompt, program, args)?;
+                    run_with_input(elevator, prompt, &mut command, stdin, timeout)?
+                }
+            }
+        }
+        _ => {
+            let mut command = elevated_command(elevator, prompt, program, args)?;
+            run_with_input(elevator, prompt, &mut command, stdin, timeout)?
+        }
+    };
+    let non_interactive = matches!(
+        prompt,
+        PasswordPrompt::NonInteractive | PasswordPrompt::Callback(_)
+    );
+    if non_interactive && !output.status.success() && elevator.password_required(&output.stderr) {
+        return Err(Error::PasswordRequired);
+    }
+    Ok(output)
+}
+
+/// Runs `program` through `elevator`, passing the password from `callback` in the same invocation.
+///
+/// Returns `None` if the elevator cannot read the password from stdin.
+fn run_with_password(
+    elevator: &Arc<dyn Elevator>,
+    prompt: &PasswordPrompt,
+    callback: &(dyn Fn() -> Option<Vec<u8>> + Send + Sync),
+    program: &OsStr,
+    args: &[&OsStr],
+    stdin: Option<&[u8]>,
+    timeout: Option<Duration>,
+) -> Result<Option<Output>, Error> {
+    let Some(mut check) = elevator.password_command(OsStr::new("true"), &[]) else {
+        return Ok(None);
+    };
+    let unsupported = || Error::UnsupportedPrompt(elevator.name().to_string());
+
+    // パスワードが不要な場合に渡すとコマンドの入力に混ざってしまうので、空の入力で先に確かめる
+    let needed = !run_with_input(elevator, prompt, &mut check, None, timeout)?
+        .status
+        .success();
+    if !needed {
+        let mut command = elevator
+            .non_interactive_command(program, args)
+            .ok_or_else(unsupported)?;
+        return run_with_input(elevator, prompt, &mut command, stdin, timeout).map(Some);
+    }
+
+    let mut command = elevator
+        .password_command(program, args)
+        .ok_or_else(unsupported)?;
+    let mut password = callback().ok_or(Error::PasswordRequired)?;
+    let stdin = stdin.unwrap_or_default();
+    let mut input = Vec::with_capacity(password.len() + 1 + stdin.len());
+    input.extend_from_slice(&password);
+    input.push(b'\n');
+    input.extend_from_slice(stdin);
+    zeroize(&mut password);
+    let output = run_with_input(elevator, prompt, &mut command, Some(&input), timeout);
+    zeroize(&mut input);
+    output.map(Some)
+}
+
+/// Builds a command that runs `program` with `args` as root, asking for a password as `prompt` says.
+///
+/// For `PasswordPrompt::Callback`, authentication is done here before returning, and the
+/// command relies on the credentials cached by the elevator. Commands whose output is
+/// captured use `run_with_password` instead, which does not need the cache.
+pub(crate) fn elevated_command(
+    elevator: &Arc<dyn Elevator>,
+    prompt: &PasswordPrompt,
+    program: &OsStr,
+    args: &[&OsStr],
+) -> Result<Command, Error> {
+    let unsupported = || Error::UnsupportedPrompt(elevator.name().to_string());
+    match prompt {
+        PasswordPrompt::Terminal => Ok(elevator.command(program, args)),
+        PasswordPrompt::NonInteractive => elevator
+            .non_interactive_command(program, args)
+            .ok_or_else(unsupported),
+        PasswordPrompt::Askpass(askpass) => elevator
+            .askpass_command(askpass, program, args)
+            .ok_or_else(unsupported),
+        PasswordPrompt::Callback(callback) => {
+            // 先にパスワードで認証しておき、コマンド自体は非対話で実行する
+            // (パスワードとコマンドへの入力が標準入力で混ざらないようにする)
+            let mut validate = elevator.validate_command().ok_or_else(unsupported)?;
+            let mut password = callback().ok_or(Error::PasswordRequired)?;
+            let mut input = Vec::with_capacity(password.len() + 1);
+            input.extend_from_slice(&password);
+            input.push(b'\n');
+            zeroize(&mut password);
+            let output = run_with_input(elevator, prompt, &mut validate, Some(&input), None);
+            zeroize(&mut input);
+            let output = output?;
+            if !output.status.success() {
+                return Err(Error::sudo_failed(&output));
+            }
+            elevator
+                .non_interactive_command(program, args)
+                .ok_or_else(unsupported)
+        }
+    }
+}
+
+/// Runs `command` writing `stdin` to its standard input, and captures its output.
+///
+/// `command` is started by `elevator` with `prompt`, which is also used to kill it.
+fn run_with_input(
+    elevator: &Arc<dyn Elevator>,
+    prompt: &PasswordPrompt,
+    command: &mut Command,
+    stdin: Option<&[u8]>,
+    timeout: Option<Duration>,
+) -> Result<Output, Error> {
+    // 時間切れの時にまとめて kill できるようにプロセスグループを分ける
+    if prompt.own_group() {
+        command.process_group(0);
+    }
+    let child = command
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::spawn(command.get_program(), e))?;
+    PrivChild::elevated(child, OsStr::new(elevator.name()), elevator, prompt)
+        .wait_with_input(stdin, timeout)
+}
+
+/// Overwrites a buffer holding a secret with zeros.
+fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        // 最適化で消されないように volatile で書き込む
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Checks whether `haystack` contains `needle`.
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Finds an executable in `PATH`.
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|file| is_executable(file))
+}
+
+/// Checks if `file` is an executable regular file.
+fn is_executable(file: &Path) -> bool {
+    file.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Chooses the first available built-in elevator.
+fn detect_elevator() -> Arc<dyn Elevator> {
+    if find_in_path("sudo").is_some() {
+        Arc::new(Sudo::new())
+    } else if find_in_path("doas").is_some() {
+        Arc::new(Doas::new())
+    } else if find_in_path("pkexec").is_some() {
+        Arc::new(Pkexec::new())
+    } else if find_in_path("su").is_some() {
+        Arc::new(Su::new())
+    } else {
+        // 何も見つからない場合は従来通り sudo を使う
+        Arc::new(Sudo::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::write_shim;
+
+    #[test]
+    fn test_elevator_command() -> Result<(), Box<dyn std::error::Error>> {
+        // 最初のオプション以外の引数で解釈を止める sudo, doas, pkexec の代わり
+        let stop = write_shim(
+            "elevator_stop",
+            "#!/bin/sh\n\
+             while [ $# -gt 0 ]; do\n\
+             case \"$1\" in\n\
+             --) shift; break ;;\n\
+             -n) shift ;;\n\
+             -*) echo \"invalid option -- '${1#-}'\" >&2; exit 1 ;;\n\
+             *) break ;;\n\
+             esac\n\
+             done\n\
+             exec \"$@\"\n",
+        );
+        // -- まで引数を並べ替えてオプションを探す su の代わり
+        let permute = write_shim(
+            "elevator_permute",
+            "#!/bin/sh\n\
+             script= skip= end=\n\
+             for arg do\n\
+             shift\n\
+             if [ -n \"$skip\" ]; then script=$arg; skip=; continue; fi\n\
+             if [ -z \"$end\" ]; then\n\
+             case \"$arg\" in\n\
+             --) end=1; continue ;;\n\
+             -c) skip=1; continue ;;\n\
+             -*) echo \"su: invalid option -- '${arg#-}'\" >&2; exit 1 ;;\n\
+             esac\n\
+             fi\n\
+             set -- \"$@\" \"$arg\"\n\
+             done\n\
+             [ \"$1\" = root ] || exit 1\n\
+             shift\n\
+             exec sh -c \"$script\" \"$@\"\n",
+        );
+
+        let file_name =
+            std::env::temp_dir().join(format!("test_elevator_command_{}", std::process::id()));
+        let elevators: [Arc<dyn Elevator>; 4] = [
+            Arc::new(Sudo::with_program(&stop)),
+            Arc::new(Doas::with_program(&stop)),
+            Arc::new(Pkexec::with_program(&stop)),
+            Arc::new(Su::with_program(&permute)),
+        ];
+        for elevator in elevators {
+            // プログラムのオプションが elevator に解釈されずに渡る
+            std::fs::write(&file_name, b"Hello")?;
+            let args = [OsStr::new("-a"), file_name.as_os_str()];
+            let output = run_elevated_with(
+                &elevator,
+                &PasswordPrompt::Terminal,
+                OsStr::new("tee"),
+                &args,
+                Some(b", World!"),
+            )?;
+            assert!(output.status.success(), "{}: {:?}", elevator.name(), output);
+            assert_eq!(std::fs::read(&file_name)?, b"Hello, World!");
+        }
+
+        std::fs::remove_file(&file_name)?;
+        std::fs::remove_file(&stop)?;
+        std::fs::remove_file(&permute)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_prompt() {
+        // パスワードが必要で、認証をキャッシュしない sudo の代わり
+        let shim = write_shim(
+            "sudo_password",
+            "#!/bin/sh\n\
+             if [ \"$1\" = -k ]; then\n\
+             shift 5\n\
+             IFS= read -r pw || { echo 'sudo: no password was provided' >&2; exit 1; }\n\
+             [ \"$pw\" = secret ] || { echo 'sudo: 1 incorrect password attempt' >&2; exit 1; }\n\
+             exec \"$@\"\n\
+             fi\n\
+             echo 'sudo: a password is required' >&2; exit 1\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let program = OsStr::new("cat");
+
+        // 非対話モードではパスワードが必要な事をエラーで返す
+        let result = run_elevated_with(
+            &elevator,
+            &PasswordPrompt::NonInteractive,
+            program,
+            &[],
+            Some(b"data"),
+        );
+        assert!(matches!(result, Err(Error::PasswordRequired)));
+
+        // 間違ったパスワードでは認証に失敗する
+        let prompt = PasswordPrompt::callback(|| Some(b"wrong".to_vec()));
+        let result = run_elevated_with(&elevator, &prompt, program, &[], Some(b"data"));
+        assert!(matches!(result, Err(Error::PasswordRequired)));
+
+        // パスワードがコマンドの入力に混ざらない
+        let prompt = PasswordPrompt::callback(|| Some(b"secret".to_vec()));
+        let output = run_elevated_with(&elevator, &prompt, program, &[], Some(b"data")).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"data");
+
+        let _ = std::fs::remove_file(shim);
+
+        // パスワードが不要な場合はパスワードを渡さない (コマンドの入力に混ざらない)
+        let shim = write_shim(
+            "sudo_nopasswd",
+            "#!/bin/sh\n\
+             case $1 in -k) shift 5 ;; -n) shift 2 ;; *) shift ;; esac\n\
+             exec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let prompt = PasswordPrompt::callback(|| panic!("the password is not needed"));
+        let output = run_elevated_with(&elevator, &prompt, program, &[], Some(b"data")).unwrap();
+        assert_eq!(output.stdout, b"data");
+        let _ = std::fs::remove_file(shim);
+    }
+}
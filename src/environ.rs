@@ -0,0 +1,193 @@
+use crate::{has_root, is_root, target_user};
+use nix::unistd::{Uid, User};
+use std::env;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::RwLock;
+
+/// Variables kept for root commands by default. A name ending with `*` matches by prefix.
+pub const DEFAULT_ROOT_ENV: &[&str] = &[
+    "PATH",
+    "TERM",
+    "COLORTERM",
+    "LANG",
+    "LANGUAGE",
+    "LC_*",
+    "TZ",
+];
+
+/// How the environment of commands run with user permissions is prepared.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UserEnv {
+    /// Pass the environment of this process through unchanged.
+    Inherit,
+    /// Set `HOME`, `USER`, `LOGNAME` and `XDG_RUNTIME_DIR` for the target user.
+    #[default]
+    TargetUser,
+}
+
+/// How the environment of commands run with root permissions is prepared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootEnv {
+    /// Pass the environment of this process through unchanged.
+    Inherit,
+    /// Keep only the listed variables, and set `HOME`, `USER` and `LOGNAME` for root.
+    ///
+    /// A name ending with `*` matches every variable starting with the rest.
+    Allowlist(Vec<String>),
+}
+
+impl Default for RootEnv {
+    fn default() -> Self {
+        RootEnv::Allowlist(
+            DEFAULT_ROOT_ENV
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        )
+    }
+}
+
+static USER_ENV: RwLock<UserEnv> = RwLock::new(UserEnv::TargetUser);
+static ROOT_ENV: RwLock<Option<RootEnv>> = RwLock::new(None);
+
+/// Sets how the environment of commands run with user permissions is prepared.
+pub fn set_user_env(policy: UserEnv) {
+    *USER_ENV.write().unwrap() = policy;
+}
+
+/// Returns how the environment of commands run with user permissions is prepared.
+pub fn user_env() -> UserEnv {
+    USER_ENV.read().unwrap().clone()
+}
+
+/// Sets how the environment of commands run with root permissions is prepared.
+///
+/// Commands run through `sudo` get the environment the elevator prepares instead.
+pub fn set_root_env(policy: RootEnv) {
+    *ROOT_ENV.write().unwrap() = Some(policy);
+}
+
+/// Returns how the environment of commands run with root permissions is prepared.
+pub fn root_env() -> RootEnv {
+    ROOT_ENV.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Prepares the environment of `command` for the current permissions.
+///
+/// Nothing is changed if the process cannot become root, because its environment is
+/// then already that of the user.
+pub(crate) fn prepare(command: &mut Command) {
+    if is_root() {
+        prepare_root(command);
+    } else if has_root() {
+        prepare_user(command);
+    }
+}
+
+/// Prepares the environment of `command` run as the target user.
+pub(crate) fn prepare_user(command: &mut Command) {
+    if user_env() == UserEnv::Inherit {
+        return;
+    }
+
+    // sudo で起動した場合は root の HOME などが残っているので user のものにする
+    let user = match target_user() {
+        Ok(user) => user,
+        Err(_) => return,
+    };
+    let passwd = User::from_uid(user.uid).ok().flatten();
+    if let Some(passwd) = &passwd {
+        command.env("HOME", &passwd.dir);
+    }
+    if let Some(name) = user.name.or(passwd.map(|passwd| passwd.name)) {
+        command.env("USER", &name).env("LOGNAME", &name);
+    }
+    let runtime_dir = PathBuf::from(format!("/run/user/{}", user.uid));
+    if runtime_dir.is_dir() {
+        command.env("XDG_RUNTIME_DIR", runtime_dir);
+    } else {
+        command.env_remove("XDG_RUNTIME_DIR");
+    }
+}
+
+/// Prepares the environment of `command` run as root.
+fn prepare_root(command: &mut Command) {
+    let allowlist = match root_env() {
+        RootEnv::Inherit => return,
+        RootEnv::Allowlist(allowlist) => allowlist,
+    };
+
+    // LD_PRELOAD などが root のコマンドに渡らないように許可したものだけを残す
+    command.env_clear();
+    for (key, value) in env::vars_os() {
+        if allowed(&allowlist, &key) {
+            command.env(key, value);
+        }
+    }
+    if let Ok(Some(root)) = User::from_uid(Uid::from_raw(0)) {
+        command
+            .env("HOME", &root.dir)
+            .env("USER", &root.name)
+            .env("LOGNAME", &root.name);
+    }
+}
+
+/// Checks if a variable name matches the allowlist.
+fn allowed(allowlist: &[String], key: &OsStr) -> bool {
+    let key = match key.to_str() {
+        Some(key) => key,
+        None => return false,
+    };
+    allowlist.iter().any(|name| match name.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed() {
+        let allowlist = RootEnv::default();
+        let allowlist = match &allowlist {
+            RootEnv::Allowlist(allowlist) => allowlist,
+            RootEnv::Inherit => unreachable!(),
+        };
+        assert!(allowed(allowlist, OsStr::new("PATH")));
+        assert!(allowed(allowlist, OsStr::new("LC_ALL")));
+        assert!(!allowed(allowlist, OsStr::new("PATHX")));
+        assert!(!allowed(allowlist, OsStr::new("LD_PRELOAD")));
+    }
+
+    #[test]
+    fn test_prepare() {
+        // 許可したもの以外は root のコマンドに渡さない
+        let mut command = Command::new("true");
+        prepare_root(&mut command);
+        let mut allowlist: Vec<String> = DEFAULT_ROOT_ENV
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        allowlist.extend(["HOME", "USER", "LOGNAME"].map(String::from));
+        for (key, value) in command.get_envs() {
+            assert!(allowed(&allowlist, key));
+            assert!(value.is_some());
+        }
+
+        if let Ok(user) = target_user() {
+            let mut command = Command::new("true");
+            prepare_user(&mut command);
+            let envs: Vec<_> = command.get_envs().collect();
+            if let Some(name) = user.name {
+                assert!(envs.contains(&(OsStr::new("USER"), Some(OsStr::new(&name)))));
+            }
+            if let Ok(Some(passwd)) = User::from_uid(user.uid) {
+                assert!(envs.contains(&(OsStr::new("HOME"), Some(passwd.dir.as_os_str()))));
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use nix::errno::Errno;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Output};
+use std::time::Duration;
+
+/// Errors returned by this crate.
+///
+/// New variants may be added, so matches need a wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The process does not have root permission.
+    NoRootPermission,
+    /// Root permission is only available through sudo, but sudo is not allowed.
+    SudoNotAllowed,
+    /// An environment variable set by sudo is missing.
+    MissingSudoEnv(String),
+    /// `SUDO_UID` or `SUDO_GID` is not a valid ID.
+    InvalidSudoUid,
+    /// The user given by `JELLY_UIDMNG_USER` is invalid or unknown.
+    InvalidTargetUser(String),
+    /// No resolver could determine the invoking user.
+    NoTargetUser,
+    /// The elevator needs a password, but prompting is disabled or no password was given.
+    PasswordRequired,
+    /// The elevator does not support the configured password prompt.
+    UnsupportedPrompt(String),
+    /// A command executed with sudo exited with a failure status.
+    SudoFailed { status: ExitStatus, stderr: String },
+    /// The privileged helper could not be started or its connection failed.
+    Helper(String),
+    /// A command run with sudo produced output that could not be parsed.
+    UnexpectedOutput(String),
+    /// An I/O operation on `path` failed.
+    Io { path: PathBuf, source: io::Error },
+    /// `program` could not be started, for example because it is not installed.
+    Spawn {
+        program: OsString,
+        source: io::Error,
+    },
+    /// Only `written` of `len` bytes were written to `path` in a single write.
+    ShortWrite {
+        path: PathBuf,
+        written: usize,
+        len: usize,
+    },
+    /// The content of `path` could not be parsed as a value.
+    Parse {
+        path: PathBuf,
+        content: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A command did not finish within `timeout` and was killed.
+    ///
+    /// `output` holds what the command wrote before it was killed. If it could not be killed,
+    /// `kill_error` holds the reason, and the command may still be running (`output.status`
+    /// is then not its exit status).
+    TimedOut {
+        timeout: Duration,
+        output: Output,
+        kill_error: Option<Box<Error>>,
+    },
+    /// A system call failed.
+    Nix(Errno),
+}
+
+impl Error {
+    /// Creates an `Io` error for `path`.
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Creates a `Spawn` error for `program`.
+    pub(crate) fn spawn(program: impl Into<OsString>, source: io::Error) -> Self {
+        Error::Spawn {
+            program: program.into(),
+            source,
+        }
+    }
+
+    /// Creates a `SudoFailed` error from the output of a sudo command.
+    pub(crate) fn sudo_failed(output: &Output) -> Self {
+        Error::SudoFailed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoRootPermission => write!(f, "don't have root permission"),
+            Error::SudoNotAllowed => {
+                write!(f, "don't have root permission and sudo is not allowed")
+            }
+            Error::MissingSudoEnv(var) => write!(f, "environment variable {} is not set", var),
+            Error::InvalidSudoUid => write!(f, "invalid SUDO_UID or SUDO_GID"),
+            Error::InvalidTargetUser(user) => write!(f, "invalid target user: {}", user),
+            Error::NoTargetUser => write!(f, "could not determine the user to change to"),
+            Error::PasswordRequired => write!(f, "a password is required to elevate privileges"),
+            Error::UnsupportedPrompt(name) => {
+                write!(f, "{} does not support the password prompt", name)
+            }
+            Error::SudoFailed { status, stderr } => {
+                write!(f, "sudo failed ({})", status)?;
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            Error::Helper(message) => write!(f, "privileged helper failed: {}", message),
+            Error::UnexpectedOutput(output) => write!(f, "unexpected command output: {}", output),
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Spawn { program, source } => {
+                write!(f, "cannot run {}: {}", program.to_string_lossy(), source)
+            }
+            Error::ShortWrite { path, written, len } => write!(
+                f,
+                "{}: short write ({} of {} bytes)",
+                path.display(),
+                written,
+                len
+            ),
+            Error::Parse {
+                path,
+                content,
+                source,
+            } => write!(
+                f,
+                "{}: cannot parse {:?}: {}",
+                path.display(),
+                content,
+                source
+            ),
+            Error::TimedOut {
+                timeout,
+                kill_error: None,
+                ..
+            } => write!(f, "command timed out after {:?}", timeout),
+            Error::TimedOut {
+                timeout,
+                kill_error: Some(e),
+                ..
+            } => write!(
+                f,
+                "command timed out after {:?} and could not be killed: {}",
+                timeout, e
+            ),
+            Error::Nix(errno) => write!(f, "{}", errno),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Spawn { source, .. } => Some(source),
+            Error::Parse { source, .. } => Some(source.as_ref()),
+            Error::Nix(errno) => Some(errno),
+            _ => None,
+        }
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(errno: Errno) -> Self {
+        Error::Nix(errno)
+    }
+}
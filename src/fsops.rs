@@ -0,0 +1,617 @@
+use crate::{elevator, giveback};
+use crate::{is_root, with_root, with_user, Elevator, Error};
+use nix::sys::stat::{makedev, mknod as nix_mknod, Mode as FileMode, SFlag};
+use nix::unistd::{eaccess, AccessFlags, Gid, Uid};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::result::Result;
+use std::sync::Arc;
+
+/// Type of a special file created by `mknod_root`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Character device.
+    Char { major: u64, minor: u64 },
+    /// Block device.
+    Block { major: u64, minor: u64 },
+    /// Named pipe.
+    Fifo,
+}
+
+// fs::remove_dir_all と同じくディレクトリ以外は消さない (シンボリックリンクはリンク自体を消す)
+// $1: path
+const REMOVE_DIR_ALL_SCRIPT: &str = r#"
+if [ ! -L "$1" ] && [ -e "$1" ] && [ ! -d "$1" ]; then
+    echo "rm: cannot remove '$1': Not a directory" >&2
+    exit 1
+fi
+exec rm -r -- "$1"
+"#;
+
+// fs::rename と同じく別のファイルシステムへはコピーせずに失敗させる
+// $1: from, $2: to
+const RENAME_SCRIPT: &str = r#"
+from_dev=$(stat -c %d -- "$1") || exit 1
+to_dev=$(stat -L -c %d -- "$(dirname -- "$2")") || exit 1
+if [ "$from_dev" != "$to_dev" ]; then
+    echo "mv: cannot move '$1' to '$2': Invalid cross-device link" >&2
+    exit 1
+fi
+exec mv -f -T -- "$1" "$2"
+"#;
+
+/// A filesystem operation that can be done directly or through `sudo`.
+enum FsOp<'a> {
+    CreateDirAll(&'a Path),
+    RemoveFile(&'a Path),
+    RemoveDirAll(&'a Path),
+    Rename(&'a Path, &'a Path),
+    Copy(&'a Path, &'a Path),
+    Symlink(&'a Path, &'a Path),
+    Mknod(&'a Path, NodeKind, u32),
+    Chmod(&'a Path, u32),
+    Chown(&'a Path, Option<Uid>, Option<Gid>),
+}
+
+impl FsOp<'_> {
+    /// Runs the operation with the current permissions.
+    fn run(&self) -> Result<(), Error> {
+        match *self {
+            FsOp::CreateDirAll(path) => fs::create_dir_all(path).map_err(|e| Error::io(path, e)),
+            FsOp::RemoveFile(path) => fs::remove_file(path).map_err(|e| Error::io(path, e)),
+            FsOp::RemoveDirAll(path) => fs::remove_dir_all(path).map_err(|e| Error::io(path, e)),
+            FsOp::Rename(from, to) => {
+                fs::rename(from, to).map_err(|e| Error::io(failing_path(from, to, true), e))
+            }
+            FsOp::Copy(from, to) => fs::copy(from, to)
+                .map(drop)
+                .map_err(|e| Error::io(failing_path(from, to, false), e)),
+            FsOp::Symlink(original, link) => {
+                std::os::unix::fs::symlink(original, link).map_err(|e| Error::io(link, e))
+            }
+            FsOp::Mknod(path, kind, perm) => {
+                let (kind, dev) = match kind {
+                    NodeKind::Char { major, minor } => (SFlag::S_IFCHR, makedev(major, minor)),
+                    NodeKind::Block { major, minor } => (SFlag::S_IFBLK, makedev(major, minor)),
+                    NodeKind::Fifo => (SFlag::S_IFIFO, 0),
+                };
+                let with_path = |e: io::Error| Error::io(path, e);
+                nix_mknod(path, kind, FileMode::from_bits_truncate(perm), dev)
+                    .map_err(|e| with_path(e.into()))?;
+
+                // `mknod -m` と同じく umask に関係なく perm にする
+                fs::set_permissions(path, fs::Permissions::from_mode(perm)).map_err(with_path)
+            }
+            FsOp::Chmod(path, perm) => fs::set_permissions(path, fs::Permissions::from_mode(perm))
+                .map_err(|e| Error::io(path, e)),
+            FsOp::Chown(path, owner, group) => {
+                std::os::unix::fs::chown(path, owner.map(Uid::as_raw), group.map(Gid::as_raw))
+                    .map_err(|e| Error::io(path, e))
+            }
+        }
+    }
+
+    /// Runs the operation with a command through `elevator` (or the configured one).
+    ///
+    /// The commands are chosen to fail where the direct operation fails: `RemoveDirAll`
+    /// refuses anything but a directory, and `Rename` refuses to move across filesystems
+    /// (judged by the device numbers, so a move between bind mounts of the same filesystem
+    /// is still done by `mv`).
+    fn run_elevated(&self, elevator: Option<&Arc<dyn Elevator>>) -> Result<(), Error> {
+        // パスは全て `--` の後ろに個別の引数として渡す
+        let (program, options, paths): (&str, &[&str], Vec<&OsStr>) = match *self {
+            FsOp::CreateDirAll(path) => ("mkdir", &["-p"], vec![path.as_os_str()]),
+            FsOp::RemoveFile(path) => ("rm", &[], vec![path.as_os_str()]),
+            FsOp::RemoveDirAll(path) => {
+                let args = [path.as_os_str()];
+                elevator::run_script(elevator, REMOVE_DIR_ALL_SCRIPT, &args, None)?;
+                return Ok(());
+            }
+            FsOp::Rename(from, to) => {
+                let args = [from.as_os_str(), to.as_os_str()];
+                elevator::run_script(elevator, RENAME_SCRIPT, &args, None)?;
+                return Ok(());
+            }
+            FsOp::Copy(from, to) => ("cp", &["-T"], vec![from.as_os_str(), to.as_os_str()]),
+            FsOp::Symlink(original, link) => (
+                "ln",
+                &["-s", "-T"],
+                vec![original.as_os_str(), link.as_os_str()],
+            ),
+            FsOp::Mknod(path, kind, perm) => {
+                return mknod_elevated(elevator, path, kind, perm);
+            }
+            FsOp::Chmod(path, perm) => {
+                let perm = OsString::from(format!("{:04o}", perm));
+                let args = [OsStr::new("--"), &perm, path.as_os_str()];
+                elevator::run_checked(elevator, OsStr::new("chmod"), &args, None)?;
+                return Ok(());
+            }
+            FsOp::Chown(path, owner, group) => {
+                // 先頭の + で数値として扱わせ、ユーザー名として解釈されないようにする
+                let owner = owner.map(|uid| format!("+{}", uid)).unwrap_or_default();
+                let group = group.map(|gid| format!(":+{}", gid)).unwrap_or_default();
+                let spec = OsString::from(owner + &group);
+                if spec.is_empty() {
+                    return Ok(());
+                }
+                let args = [OsStr::new("--"), &spec, path.as_os_str()];
+                elevator::run_checked(elevator, OsStr::new("chown"), &args, None)?;
+                return Ok(());
+            }
+        };
+        let mut args: Vec<&OsStr> = options.iter().map(OsStr::new).collect();
+        args.push(OsStr::new("--"));
+        args.extend(paths);
+        elevator::run_checked(elevator, OsStr::new(program), &args, None)?;
+        Ok(())
+    }
+
+    /// Returns the path this operation may create.
+    fn created(&self) -> Option<&Path> {
+        match *self {
+            FsOp::CreateDirAll(path) | FsOp::Mknod(path, _, _) => Some(path),
+            FsOp::Copy(_, to) => Some(to),
+            FsOp::Symlink(_, link) => Some(link),
+            _ => None,
+        }
+    }
+
+    /// Runs `f` and records the created path for `give_back_all`.
+    fn track(&self, f: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+        match self.created() {
+            Some(path) => giveback::track(path, f),
+            None => f(),
+        }
+    }
+
+    /// Runs the operation using `sudo` permissions.
+    fn run_sudo(&self) -> Result<(), Error> {
+        self.track(|| self.run_elevated(None))
+    }
+
+    /// Runs the operation using user permissions.
+    fn run_user(&self) -> Result<(), Error> {
+        with_user(|| self.run())
+    }
+
+    /// Runs the operation using root permissions, or `sudo` if allowed.
+    fn run_root(&self) -> Result<(), Error> {
+        self.track(|| with_root(|| self.run(), || self.run_elevated(None)))
+    }
+
+    /// Runs the operation and tries to use root permissions if it fails.
+    fn run_try(&self) -> Result<(), Error> {
+        self.track(|| {
+            let result = self.run();
+            if result.is_err() && !is_root() {
+                self.run_root()
+            } else {
+                result
+            }
+        })
+    }
+}
+
+/// Returns which of `from` and `to` made a rename or copy fail.
+///
+/// `fs::rename` and `fs::copy` do not tell which path the error came from, so this checks
+/// whether `from` itself can be renamed (or read for a copy) with the current permissions.
+fn failing_path<'a>(from: &'a Path, to: &'a Path, rename: bool) -> &'a Path {
+    let from_ok = if rename {
+        let dir = match from.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::symlink_metadata(from).is_ok() && eaccess(dir, AccessFlags::W_OK).is_ok()
+    } else {
+        fs::metadata(from).is_ok_and(|metadata| metadata.is_file()) && File::open(from).is_ok()
+    };
+    if from_ok {
+        to
+    } else {
+        from
+    }
+}
+
+/// Creates a special file with `mknod` through `elevator` (or the configured one).
+fn mknod_elevated(
+    elevator: Option<&Arc<dyn Elevator>>,
+    path: &Path,
+    kind: NodeKind,
+    perm: u32,
+) -> Result<(), Error> {
+    let mut args = vec![
+        OsString::from("-m"),
+        OsString::from(format!("{:04o}", perm)),
+        OsString::from("--"),
+        path.as_os_str().to_os_string(),
+    ];
+    match kind {
+        NodeKind::Char { major, minor } => args.extend([
+            "c".into(),
+            major.to_string().into(),
+            minor.to_string().into(),
+        ]),
+        NodeKind::Block { major, minor } => args.extend([
+            "b".into(),
+            major.to_string().into(),
+            minor.to_string().into(),
+        ]),
+        NodeKind::Fifo => args.push("p".into()),
+    }
+    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+    elevator::run_checked(elevator, OsStr::new("mknod"), &args, None)?;
+    Ok(())
+}
+
+/// Creates a directory and all of its missing parents.
+pub fn create_dir_all(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::CreateDirAll(path.as_ref()).run()
+}
+
+/// Creates a directory and all of its missing parents using `sudo` permissions.
+pub fn create_dir_all_sudo(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::CreateDirAll(path.as_ref()).run_sudo()
+}
+
+/// Creates a directory and all of its missing parents using user permissions.
+pub fn create_dir_all_user(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::CreateDirAll(path.as_ref()).run_user()
+}
+
+/// Creates a directory and all of its missing parents using root permissions.
+pub fn create_dir_all_root(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::CreateDirAll(path.as_ref()).run_root()
+}
+
+/// Creates a directory and its parents, trying root permissions if that fails.
+pub fn create_dir_all_try(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::CreateDirAll(path.as_ref()).run_try()
+}
+
+/// Removes a file.
+pub fn remove_file(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveFile(path.as_ref()).run()
+}
+
+/// Removes a file using `sudo` permissions.
+pub fn remove_file_sudo(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveFile(path.as_ref()).run_sudo()
+}
+
+/// Removes a file using user permissions.
+pub fn remove_file_user(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveFile(path.as_ref()).run_user()
+}
+
+/// Removes a file using root permissions.
+pub fn remove_file_root(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveFile(path.as_ref()).run_root()
+}
+
+/// Removes a file and tries to use root permissions if that fails.
+pub fn remove_file_try(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveFile(path.as_ref()).run_try()
+}
+
+/// Removes a directory and all of its contents.
+pub fn remove_dir_all(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveDirAll(path.as_ref()).run()
+}
+
+/// Removes a directory and all of its contents using `sudo` permissions.
+pub fn remove_dir_all_sudo(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveDirAll(path.as_ref()).run_sudo()
+}
+
+/// Removes a directory and all of its contents using user permissions.
+pub fn remove_dir_all_user(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveDirAll(path.as_ref()).run_user()
+}
+
+/// Removes a directory and all of its contents using root permissions.
+pub fn remove_dir_all_root(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveDirAll(path.as_ref()).run_root()
+}
+
+/// Removes a directory and its contents, trying root permissions if that fails.
+pub fn remove_dir_all_try(path: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::RemoveDirAll(path.as_ref()).run_try()
+}
+
+/// Renames a file or directory, replacing `to` if it exists.
+pub fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Rename(from.as_ref(), to.as_ref()).run()
+}
+
+/// Renames a file or directory using `sudo` permissions.
+pub fn rename_sudo(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Rename(from.as_ref(), to.as_ref()).run_sudo()
+}
+
+/// Renames a file or directory using user permissions.
+pub fn rename_user(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Rename(from.as_ref(), to.as_ref()).run_user()
+}
+
+/// Renames a file or directory using root permissions.
+pub fn rename_root(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Rename(from.as_ref(), to.as_ref()).run_root()
+}
+
+/// Renames a file or directory and tries to use root permissions if that fails.
+pub fn rename_try(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Rename(from.as_ref(), to.as_ref()).run_try()
+}
+
+/// Copies the contents and permissions of a file.
+pub fn copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Copy(from.as_ref(), to.as_ref()).run()
+}
+
+/// Copies a file using `sudo` permissions.
+pub fn copy_sudo(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Copy(from.as_ref(), to.as_ref()).run_sudo()
+}
+
+/// Copies a file using user permissions.
+pub fn copy_user(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Copy(from.as_ref(), to.as_ref()).run_user()
+}
+
+/// Copies a file using root permissions.
+pub fn copy_root(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Copy(from.as_ref(), to.as_ref()).run_root()
+}
+
+/// Copies a file and tries to use root permissions if that fails.
+pub fn copy_try(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Copy(from.as_ref(), to.as_ref()).run_try()
+}
+
+/// Creates a symbolic link `link` pointing to `original`.
+pub fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Symlink(original.as_ref(), link.as_ref()).run()
+}
+
+/// Creates a symbolic link using `sudo` permissions.
+pub fn symlink_sudo(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Symlink(original.as_ref(), link.as_ref()).run_sudo()
+}
+
+/// Creates a symbolic link using user permissions.
+pub fn symlink_user(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Symlink(original.as_ref(), link.as_ref()).run_user()
+}
+
+/// Creates a symbolic link using root permissions.
+pub fn symlink_root(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Symlink(original.as_ref(), link.as_ref()).run_root()
+}
+
+/// Creates a symbolic link and tries to use root permissions if that fails.
+pub fn symlink_try(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<(), Error> {
+    FsOp::Symlink(original.as_ref(), link.as_ref()).run_try()
+}
+
+/// Creates a device node or named pipe with exactly the permission bits `perm`.
+pub fn mknod(path: impl AsRef<Path>, kind: NodeKind, perm: u32) -> Result<(), Error> {
+    FsOp::Mknod(path.as_ref(), kind, perm).run()
+}
+
+/// Creates a device node or named pipe using `sudo` permissions.
+pub fn mknod_sudo(path: impl AsRef<Path>, kind: NodeKind, perm: u32) -> Result<(), Error> {
+    FsOp::Mknod(path.as_ref(), kind, perm).run_sudo()
+}
+
+/// Creates a device node or named pipe using root permissions.
+pub fn mknod_root(path: impl AsRef<Path>, kind: NodeKind, perm: u32) -> Result<(), Error> {
+    FsOp::Mknod(path.as_ref(), kind, perm).run_root()
+}
+
+/// Changes the permission bits of a file.
+pub fn chmod(path: impl AsRef<Path>, perm: u32) -> Result<(), Error> {
+    FsOp::Chmod(path.as_ref(), perm).run()
+}
+
+/// Changes the permission bits of a file using `sudo` permissions.
+pub fn chmod_sudo(path: impl AsRef<Path>, perm: u32) -> Result<(), Error> {
+    FsOp::Chmod(path.as_ref(), perm).run_sudo()
+}
+
+/// Changes the permission bits of a file using user permissions.
+pub fn chmod_user(path: impl AsRef<Path>, perm: u32) -> Result<(), Error> {
+    FsOp::Chmod(path.as_ref(), perm).run_user()
+}
+
+/// Changes the permission bits of a file using root permissions.
+pub fn chmod_root(path: impl AsRef<Path>, perm: u32) -> Result<(), Error> {
+    FsOp::Chmod(path.as_ref(), perm).run_root()
+}
+
+/// Changes the permission bits of a file and tries to use root permissions if that fails.
+pub fn chmod_try(path: impl AsRef<Path>, perm: u32) -> Result<(), Error> {
+    FsOp::Chmod(path.as_ref(), perm).run_try()
+}
+
+/// Changes the owner and group of a file. `None` leaves it as is.
+pub fn chown(path: impl AsRef<Path>, owner: Option<Uid>, group: Option<Gid>) -> Result<(), Error> {
+    FsOp::Chown(path.as_ref(), owner, group).run()
+}
+
+/// Changes the owner and group of a file using `sudo` permissions.
+pub fn chown_sudo(
+    path: impl AsRef<Path>,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+) -> Result<(), Error> {
+    FsOp::Chown(path.as_ref(), owner, group).run_sudo()
+}
+
+/// Changes the owner and group of a file using user permissions.
+pub fn chown_user(
+    path: impl AsRef<Path>,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+) -> Result<(), Error> {
+    FsOp::Chown(path.as_ref(), owner, group).run_user()
+}
+
+/// Changes the owner and group of a file using root permissions.
+pub fn chown_root(
+    path: impl AsRef<Path>,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+) -> Result<(), Error> {
+    FsOp::Chown(path.as_ref(), owner, group).run_root()
+}
+
+/// Changes the owner and group of a file and tries to use root permissions if that fails.
+pub fn chown_try(
+    path: impl AsRef<Path>,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+) -> Result<(), Error> {
+    FsOp::Chown(path.as_ref(), owner, group).run_try()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{has_root, metadata, read, write, Sudo};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_fs_ops() -> Result<(), Box<dyn std::error::Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_fsops",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+
+        for sudo in [false, true] {
+            let run = |op: FsOp| {
+                if sudo {
+                    op.run_elevated(Some(&elevator))
+                } else {
+                    op.run()
+                }
+            };
+
+            let dir =
+                std::env::temp_dir().join(format!("test_fsops_{}_{}", sudo, std::process::id()));
+            let sub = dir.join("-a b/c");
+            run(FsOp::CreateDirAll(&sub))?;
+            assert!(sub.is_dir());
+
+            let file = sub.join("file");
+            write(&file, b"data")?;
+            let copied = sub.join("copied");
+            run(FsOp::Copy(&file, &copied))?;
+            assert_eq!(read(&copied)?, b"data");
+
+            // rename は既存のファイルを置き換える
+            run(FsOp::Rename(&copied, &file))?;
+            assert!(!copied.exists());
+            assert_eq!(read(&file)?, b"data");
+
+            let link = dir.join("link");
+            run(FsOp::Symlink(&file, &link))?;
+            assert_eq!(std::fs::read_link(&link)?, file);
+
+            let fifo = dir.join("fifo");
+            run(FsOp::Mknod(&fifo, NodeKind::Fifo, 0o640))?;
+            let fifo_metadata = metadata(&fifo)?;
+            assert_eq!(fifo_metadata.permissions(), 0o640);
+            if has_root() {
+                let null = dir.join("null");
+                run(FsOp::Mknod(
+                    &null,
+                    NodeKind::Char { major: 1, minor: 3 },
+                    0o666,
+                ))?;
+                assert_eq!(std::fs::read(&null)?, b"");
+            }
+
+            run(FsOp::Chmod(&file, 0o604))?;
+            assert_eq!(metadata(&file)?.permissions(), 0o604);
+            if has_root() {
+                run(FsOp::Chown(&file, Some(Uid::from_raw(1234)), None))?;
+                run(FsOp::Chown(&file, None, Some(Gid::from_raw(5678))))?;
+                let file_metadata = metadata(&file)?;
+                assert_eq!(file_metadata.uid, Uid::from_raw(1234));
+                assert_eq!(file_metadata.gid, Gid::from_raw(5678));
+            }
+
+            run(FsOp::RemoveFile(&link))?;
+            assert!(!link.exists());
+            assert!(run(FsOp::RemoveFile(&link)).is_err());
+
+            // 直接の操作では失敗した側のパスをエラーにする
+            if !sudo {
+                let missing = dir.join("missing");
+                let error_path = |result: Result<(), Error>| match result {
+                    Err(Error::Io { path, .. }) => path,
+                    result => panic!("unexpected result: {:?}", result),
+                };
+                assert_eq!(error_path(run(FsOp::Copy(&missing, &fifo))), missing);
+                assert_eq!(
+                    error_path(run(FsOp::Copy(&file, &missing.join("to")))),
+                    missing.join("to")
+                );
+                assert_eq!(error_path(run(FsOp::Rename(&missing, &fifo))), missing);
+                assert_eq!(
+                    error_path(run(FsOp::Rename(&file, &missing.join("to")))),
+                    missing.join("to")
+                );
+            }
+
+            run(FsOp::RemoveDirAll(&dir))?;
+            assert!(!dir.exists());
+        }
+        std::fs::remove_file(&shim)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_ops_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_fsops_mismatch",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let file = std::env::temp_dir().join(format!("test_fsops_mismatch_{}", std::process::id()));
+        let shm = Path::new("/dev/shm").join(file.file_name().unwrap());
+        let cross_device = std::fs::metadata("/dev/shm")
+            .is_ok_and(|shm| shm.dev() != std::fs::metadata(std::env::temp_dir()).unwrap().dev());
+
+        for sudo in [false, true] {
+            let run = |op: FsOp| {
+                if sudo {
+                    op.run_elevated(Some(&elevator))
+                } else {
+                    op.run()
+                }
+            };
+
+            // remove_dir_all はファイルを消さない
+            write(&file, b"data")?;
+            assert!(run(FsOp::RemoveDirAll(&file)).is_err());
+            assert!(file.exists());
+
+            // rename は別のファイルシステムへコピーしない
+            if cross_device {
+                assert!(run(FsOp::Rename(&file, &shm)).is_err());
+                assert!(file.exists());
+                assert!(!shm.exists());
+            }
+            std::fs::remove_file(&file)?;
+        }
+        std::fs::remove_file(&shim)?;
+        Ok(())
+    }
+}
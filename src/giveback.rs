@@ -0,0 +1,320 @@
+use crate::elevator;
+use crate::{target_user, with_root, Elevator, Error, TargetUser};
+use nix::dir::Dir;
+use nix::errno::Errno;
+use nix::fcntl::{AtFlags, OFlag};
+use nix::sys::stat::Mode as FileMode;
+use nix::unistd::{fchown, fchownat};
+use std::cell::Cell;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+static TRACK_CREATED: AtomicBool = AtomicBool::new(false);
+static CREATED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static TRACK_IN_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets whether paths created by root operations are recorded for `give_back_all`.
+///
+/// When enabled, files and directories that did not exist before and are created by
+/// the `*_root`, `*_sudo` and `*_try` functions are recorded.
+pub fn set_track_created(value: bool) {
+    TRACK_CREATED.store(value, Ordering::SeqCst);
+}
+
+/// Returns whether paths created by root operations are recorded.
+pub fn track_created() -> bool {
+    TRACK_CREATED.load(Ordering::SeqCst)
+}
+
+/// Runs `f` with paths created by root operations recorded, only in the calling thread.
+///
+/// Unlike `set_track_created`, this does not affect operations in other threads.
+pub fn with_track_created<T>(f: impl FnOnce() -> T) -> T {
+    // panic しても元に戻す
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            TRACK_IN_THREAD.set(self.0);
+        }
+    }
+    let _restore = Restore(TRACK_IN_THREAD.replace(true));
+    f()
+}
+
+/// Returns the recorded paths that have not been given back yet.
+pub fn created_paths() -> Vec<PathBuf> {
+    CREATED.lock().unwrap().clone()
+}
+
+/// Runs `f`, which may create `path`, and records `path` if it was created.
+pub(crate) fn track<T>(path: &Path, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    if !track_created() && !TRACK_IN_THREAD.get() {
+        return f();
+    }
+
+    // 既にあったものは所有者を変えてはいけないので、無かった場合だけ記録する
+    // (調べられない場合も既にあったものとして扱う)
+    let created = first_missing(path);
+    let result = f()?;
+    if let Some(created) = created {
+        if fs::symlink_metadata(&created).is_ok() {
+            // 記録済みのディレクトリの中であれば一緒に戻すので記録しない
+            let mut paths = CREATED.lock().unwrap();
+            if !paths.iter().any(|path| created.starts_with(path)) {
+                paths.push(created);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the outermost missing path among `path` and its ancestors.
+fn first_missing(path: &Path) -> Option<PathBuf> {
+    let path = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => env::current_dir().ok()?.join(path),
+    };
+
+    // create_dir_all などで途中のディレクトリも作られる事がある
+    let mut missing = None;
+    for ancestor in path.ancestors() {
+        match fs::symlink_metadata(ancestor) {
+            Ok(_) => return missing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                missing = Some(ancestor.to_path_buf());
+            }
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Changes the owner of `path` and everything under it to `user`, without following symlinks.
+///
+/// The tree is walked with file descriptors relative to each parent directory, so replacing
+/// a directory with a symlink during the walk does not redirect the change elsewhere.
+fn chown_recursive(path: &Path, user: &TargetUser) -> Result<(), Error> {
+    chown_at(None, path, path, user)
+}
+
+/// Changes the owner of `name` under the directory `parent` recursively.
+///
+/// `path` is the full path used in errors.
+fn chown_at(
+    parent: Option<RawFd>,
+    name: &Path,
+    path: &Path,
+    user: &TargetUser,
+) -> Result<(), Error> {
+    let (uid, gid) = (Some(user.uid), Some(user.gid));
+    let with_path = |e: Errno| Error::io(path, e.into());
+
+    // シンボリックリンクはたどらずにディレクトリとして開けたものだけを降りる
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let mut dir = match Dir::openat(parent, name, flags, FileMode::empty()) {
+        Ok(dir) => dir,
+        Err(Errno::ENOTDIR | Errno::ELOOP) => {
+            return fchownat(parent, name, uid, gid, AtFlags::AT_SYMLINK_NOFOLLOW)
+                .map_err(with_path);
+        }
+        Err(e) => return Err(with_path(e)),
+    };
+
+    // 開いたディレクトリ自体を変更するので、途中で入れ替えられても影響しない
+    let fd = dir.as_raw_fd();
+    fchown(fd, uid, gid).map_err(with_path)?;
+    let mut names = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry.map_err(with_path)?;
+        let name = entry.file_name().to_bytes();
+        if name != b"." && name != b".." {
+            names.push(PathBuf::from(OsStr::from_bytes(name)));
+        }
+    }
+    for name in names {
+        chown_at(Some(fd), &name, &path.join(&name), user)?;
+    }
+    Ok(())
+}
+
+/// Changes the owner recursively with `chown -R` through `elevator` (or the configured one).
+fn chown_recursive_elevated(
+    elevator: Option<&Arc<dyn Elevator>>,
+    path: &Path,
+    user: &TargetUser,
+) -> Result<(), Error> {
+    // -R は既定でシンボリックリンクをたどらない (-P)
+    let owner = OsString::from(format!("{}:{}", user.uid, user.gid));
+    let args = [
+        OsStr::new("-R"),
+        OsStr::new("-h"),
+        OsStr::new("--"),
+        &owner,
+        path.as_os_str(),
+    ];
+    elevator::run_checked(elevator, OsStr::new("chown"), &args, None)?;
+    Ok(())
+}
+
+/// Gives a path and everything under it back to the invoking user.
+///
+/// The owner and group are changed to those of `target_user`, using root permissions
+/// or `sudo` if allowed.
+pub fn give_back(path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let user = target_user()?;
+    with_root(
+        || chown_recursive(path, &user),
+        || chown_recursive_elevated(None, path, &user),
+    )?;
+    CREATED
+        .lock()
+        .unwrap()
+        .retain(|created| !created.starts_with(path));
+    Ok(())
+}
+
+/// Gives every recorded path back to the invoking user and clears the record.
+///
+/// Paths that no longer exist are skipped. All paths are tried even if one fails,
+/// and the first error is returned.
+pub fn give_back_all() -> Result<(), Error> {
+    let paths = std::mem::take(&mut *CREATED.lock().unwrap());
+    let mut result = Ok(());
+    for path in paths {
+        if fs::symlink_metadata(&path).is_err() {
+            continue;
+        }
+        if let Err(e) = give_back(&path) {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Records paths created by root operations and gives them back to the user on drop.
+///
+/// Call `give_back_all` before dropping to handle errors; errors on drop are ignored.
+/// Nothing is given back if the process exits without dropping the guard
+/// (e.g. `std::process::exit` or a signal).
+pub struct GiveBackGuard {
+    track_created: bool,
+}
+
+impl GiveBackGuard {
+    /// Enables `set_track_created` and returns a guard that calls `give_back_all` on drop.
+    pub fn new() -> Self {
+        let track_created = TRACK_CREATED.swap(true, Ordering::SeqCst);
+        Self { track_created }
+    }
+}
+
+impl Default for GiveBackGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for GiveBackGuard {
+    fn drop(&mut self) {
+        // drop では返せないので失敗は無視する
+        let _ = give_back_all();
+        set_track_created(self.track_created);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{has_root, metadata, Sudo};
+    use nix::unistd::{Gid, Uid};
+
+    #[test]
+    fn test_first_missing() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("test_first_missing_{}", std::process::id()));
+        assert_eq!(first_missing(&dir.join("a/b")), Some(dir.clone()));
+        std::fs::create_dir_all(&dir)?;
+        assert_eq!(first_missing(&dir.join("a/b")), Some(dir.join("a")));
+        assert_eq!(first_missing(&dir), None);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_track_created() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() && target_user().is_ok() {
+            let dir = std::env::temp_dir().join(format!("test_track_{}", std::process::id()));
+            // 他のテストの操作は記録しないようにこのスレッドだけで記録する
+            with_track_created(|| -> Result<(), Error> {
+                crate::create_dir_all_root(dir.join("a/b"))?;
+                crate::write_root(dir.join("a/b/file"), b"root")
+            })?;
+            assert!(!track_created());
+
+            // 作成したディレクトリの中のファイルは一緒に戻す
+            let created = created_paths();
+            assert!(created.contains(&dir));
+            assert!(!created.contains(&dir.join("a/b/file")));
+            assert!(metadata(dir.join("a/b/file"))?.uid.is_root());
+            give_back(&dir)?;
+            assert!(!created_paths().contains(&dir));
+            let user = target_user()?;
+            assert_eq!(metadata(dir.join("a/b/file"))?.uid, user.uid);
+            assert_eq!(metadata(&dir)?.gid, user.gid);
+
+            std::fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_give_back() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() {
+            let user = TargetUser::new(Uid::from_raw(1234), Gid::from_raw(5678));
+            let shim = crate::tests::write_shim(
+                "sudo_give_back",
+                "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+            );
+            let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+
+            for sudo in [false, true] {
+                let dir = std::env::temp_dir().join(format!(
+                    "test_give_back_{}_{}",
+                    sudo,
+                    std::process::id()
+                ));
+                std::fs::create_dir_all(dir.join("sub"))?;
+                std::fs::write(dir.join("sub/file"), b"")?;
+                std::os::unix::fs::symlink("/etc/passwd", dir.join("link"))?;
+                if sudo {
+                    chown_recursive_elevated(Some(&elevator), &dir, &user)?;
+                } else {
+                    chown_recursive(&dir, &user)?;
+                }
+                for path in [dir.clone(), dir.join("sub"), dir.join("sub/file")] {
+                    assert_eq!(metadata(&path)?.uid, user.uid);
+                    assert_eq!(metadata(&path)?.gid, user.gid);
+                }
+
+                // シンボリックリンクの先は変更しない
+                let link = std::fs::symlink_metadata(dir.join("link"))?;
+                assert_eq!(std::os::unix::fs::MetadataExt::uid(&link), 1234);
+                assert!(metadata("/etc/passwd")?.uid.is_root());
+                std::fs::remove_dir_all(&dir)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{change_root, change_user, Error, PrivilegeLock, ROOT_GROUPS};
+use nix::unistd::{
+    getgroups, getresgid, getresuid, seteuid, setgroups, setresgid, setresuid, Gid, ResGid, ResUid,
+    Uid,
+};
+use std::result::Result;
+
+/// Real, effective and saved user/group IDs and supplementary groups of the process.
+///
+/// The root groups saved by `change_user` are recorded too, so that `change_root` after the
+/// guard restores them.
+struct Credentials {
+    uid: ResUid,
+    gid: ResGid,
+    groups: Vec<Gid>,
+    root_groups: Option<Vec<Gid>>,
+}
+
+impl Credentials {
+    /// Records the current credentials.
+    fn current() -> nix::Result<Self> {
+        Ok(Self {
+            uid: getresuid()?,
+            gid: getresgid()?,
+            groups: getgroups()?,
+            root_groups: ROOT_GROUPS.lock().unwrap().clone(),
+        })
+    }
+
+    /// Puts the recorded credentials back.
+    fn restore(&self) -> nix::Result<()> {
+        // gid の変更には root 権限が必要なので、戻れる場合は先に euid を root にする
+        let uid = getresuid()?;
+        if !uid.effective.is_root() && (uid.real.is_root() || uid.saved.is_root()) {
+            seteuid(Uid::from_raw(0))?;
+        }
+
+        // supplementary group と gid を先に戻してから uid を戻す
+        if getgroups()? != self.groups {
+            setgroups(&self.groups)?;
+        }
+        setresgid(self.gid.real, self.gid.effective, self.gid.saved)?;
+        setresuid(self.uid.real, self.uid.effective, self.uid.saved)?;
+        *ROOT_GROUPS.lock().unwrap() = self.root_groups.clone();
+        Ok(())
+    }
+}
+
+/// Keeps the process in root mode and restores the previous credentials on drop.
+pub struct RootGuard {
+    saved: Credentials,
+    // 元に戻すまで他のスレッドが権限を変更しないように保持する
+    _lock: PrivilegeLock,
+}
+
+impl RootGuard {
+    /// Changes to root and returns a guard that restores the current credentials.
+    pub fn new() -> Result<Self, Error> {
+        // 変更途中で失敗しても drop で元に戻るように先に guard を作る
+        let lock = PrivilegeLock::acquire();
+        let guard = Self {
+            saved: Credentials::current()?,
+            _lock: lock,
+        };
+        change_root()?;
+        Ok(guard)
+    }
+}
+
+impl Drop for RootGuard {
+    fn drop(&mut self) {
+        // drop (panic 中を含む) では失敗を返せないので無視する
+        let _ = self.saved.restore();
+    }
+}
+
+/// Keeps the process in user mode and restores the previous credentials on drop.
+pub struct UserGuard {
+    saved: Credentials,
+    // 元に戻すまで他のスレッドが権限を変更しないように保持する
+    _lock: PrivilegeLock,
+}
+
+impl UserGuard {
+    /// Changes to user and returns a guard that restores the current credentials.
+    pub fn new() -> Result<Self, Error> {
+        // 変更途中で失敗しても drop で元に戻るように先に guard を作る
+        let lock = PrivilegeLock::acquire();
+        let guard = Self {
+            saved: Credentials::current()?,
+            _lock: lock,
+        };
+        change_user()?;
+        Ok(guard)
+    }
+}
+
+impl Drop for UserGuard {
+    fn drop(&mut self) {
+        // drop (panic 中を含む) では失敗を返せないので無視する
+        let _ = self.saved.restore();
+    }
+}
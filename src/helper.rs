@@ -0,0 +1,989 @@
+use crate::child::proc_stat;
+use crate::elevator::{self, elevator, password_prompt};
+use crate::{command_timeout, target_user, Error, Metadata, OpenOptions, PrivChild};
+use nix::libc;
+use nix::unistd::{Gid, Uid};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::result::Result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Command line argument that starts the current executable as the privileged helper.
+pub const HELPER_ARG: &str = "--jelly-uidmng-helper";
+
+// 要求の種類
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+const OP_APPEND: u8 = 3;
+const OP_STAT: u8 = 4;
+const OP_COMMAND: u8 = 5;
+const OP_OPEN: u8 = 6;
+const OP_CONNECT: u8 = 7;
+
+// 応答の種類
+const STATUS_OK: u8 = 0;
+const STATUS_ERR: u8 = 1;
+
+// フレームの長さは 32bit で送る
+const MAX_FRAME: usize = u32::MAX as usize;
+
+/// Builds the payload of a frame.
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(kind: u8) -> Self {
+        Self { buf: vec![kind] }
+    }
+
+    fn u8(mut self, value: u8) -> Self {
+        self.buf.push(value);
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn i32(mut self, value: i32) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn i64(mut self, value: i64) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Self {
+        // 32bit に収まらない場合はフレーム全体も収まらないので、送る前にエラーになる
+        self = self.u32(u32::try_from(value.len()).unwrap_or(u32::MAX));
+        self.buf.extend_from_slice(value);
+        self
+    }
+}
+
+/// Reads fields from the payload of a frame.
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let value = self.bytes_n(N)?;
+        Ok(value.try_into().unwrap())
+    }
+
+    fn bytes_n(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated helper frame",
+            ));
+        }
+        let (value, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes_n(len)
+    }
+
+    fn os_str(&mut self) -> io::Result<&'a OsStr> {
+        Ok(OsStr::from_bytes(self.bytes()?))
+    }
+}
+
+/// Writes a frame (32-bit little endian length followed by the payload).
+///
+/// A payload longer than `u32::MAX` fails with `EFBIG` before anything is written.
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let len =
+        u32::try_from(payload.len()).map_err(|_| io::Error::from_raw_os_error(libc::EFBIG))?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(payload)
+}
+
+/// Reads a frame, returning `None` when the peer has closed the connection.
+fn read_frame(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Sends a single byte with `fd` attached as `SCM_RIGHTS`.
+fn send_fd(stream: &UnixStream, fd: RawFd) -> io::Result<()> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // cmsghdr の alignment を満たすように u64 の配列を使う
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+    }
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives the byte sent by `send_fd` and returns the attached file descriptor.
+fn recv_fd(stream: &UnixStream) -> io::Result<OwnedFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let len = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if len == 1
+            && !cmsg.is_null()
+            && (*cmsg).cmsg_level == libc::SOL_SOCKET
+            && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+        {
+            let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>());
+            return Ok(OwnedFd::from_raw_fd(fd));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no file descriptor from helper",
+    ))
+}
+
+/// Serves requests on `stream` until the connection is closed.
+///
+/// `OP_COMMAND` is refused unless `commands` is set.
+fn serve(mut stream: UnixStream, commands: bool) -> io::Result<()> {
+    while let Some(request) = read_frame(&mut stream)? {
+        let (response, file) = match handle(&request, commands) {
+            Ok(response) => response,
+            Err((path, e)) => (
+                Encoder::new(STATUS_ERR)
+                    .i32(e.raw_os_error().unwrap_or(0))
+                    .bytes(path.as_os_str().as_bytes())
+                    .bytes(e.to_string().as_bytes()),
+                None,
+            ),
+        };
+        write_frame(&mut stream, &response.buf)?;
+
+        // 開いたファイルは応答の後に fd として渡す
+        if let Some(file) = file {
+            send_fd(&stream, file.as_raw_fd())?;
+        }
+    }
+    Ok(())
+}
+
+/// Handles a request and returns the response and a file to pass, or the failed path and error.
+fn handle(request: &[u8], commands: bool) -> Result<(Encoder, Option<File>), (PathBuf, io::Error)> {
+    let mut decoder = Decoder::new(request);
+    let op = decoder.u8().map_err(|e| (PathBuf::new(), e))?;
+    let path = decoder
+        .os_str()
+        .map(Path::new)
+        .map_err(|e| (PathBuf::new(), e))?;
+    let with_path = |e| (path.to_path_buf(), e);
+    let (response, file) = handle_op(op, path, &mut decoder, commands).map_err(with_path)?;
+
+    // 4GiB を超えるファイルなどはフレームで送れないのでエラーにする
+    if response.buf.len() > MAX_FRAME {
+        return Err(with_path(io::Error::from_raw_os_error(libc::EFBIG)));
+    }
+    Ok((response, file))
+}
+
+/// Handles the operation `op` on `path`, with the rest of the request in `decoder`.
+fn handle_op(
+    op: u8,
+    path: &Path,
+    decoder: &mut Decoder,
+    commands: bool,
+) -> io::Result<(Encoder, Option<File>)> {
+    match op {
+        OP_READ => {
+            let data = fs::read(path)?;
+            Ok((Encoder::new(STATUS_OK).bytes(&data), None))
+        }
+        OP_WRITE | OP_APPEND => {
+            let data = decoder.bytes()?;
+            let mut file = if op == OP_WRITE {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+            } else {
+                fs::OpenOptions::new().append(true).open(path)
+            }?;
+            file.write_all(data)?;
+            Ok((Encoder::new(STATUS_OK), None))
+        }
+        OP_STAT => {
+            let metadata = Metadata::from(fs::metadata(path)?);
+            let response = Encoder::new(STATUS_OK)
+                .u32(metadata.mode)
+                .u32(metadata.uid.as_raw())
+                .u32(metadata.gid.as_raw())
+                .u64(metadata.size)
+                .i64(metadata.mtime);
+            Ok((response, None))
+        }
+        OP_COMMAND => {
+            // 任意のコマンドを root で実行できてしまうので許可されている場合だけ実行する
+            if !commands {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "commands are not enabled for the helper (see set_helper_commands)",
+                ));
+            }
+
+            // コマンドの場合は path にプログラムが入っている
+            let argc = decoder.u32()?;
+            let args = (0..argc)
+                .map(|_| decoder.os_str())
+                .collect::<io::Result<Vec<_>>>()?;
+            let stdin = match decoder.u8()? {
+                0 => None,
+                _ => Some(decoder.bytes()?),
+            };
+            let timeout = match decoder.u8()? {
+                0 => None,
+                _ => Some(Duration::new(decoder.u64()?, decoder.u32()?)),
+            };
+            let (output, timed_out) = run_command(path.as_os_str(), &args, stdin, timeout)?;
+            let response = Encoder::new(STATUS_OK)
+                .u8(timed_out as u8)
+                .i32(output.status.into_raw())
+                .bytes(&output.stdout)
+                .bytes(&output.stderr);
+            Ok((response, None))
+        }
+        OP_OPEN => {
+            let flags = decoder.u8()?;
+            let mode = decoder.u32()?;
+            let custom_flags = decoder.i32()?;
+            let options = OpenOptions::decode(flags, mode, custom_flags);
+            let file = options.to_std().open(path)?;
+            Ok((Encoder::new(STATUS_OK), Some(file)))
+        }
+        OP_CONNECT => {
+            // 要求を並行して処理できるように、別のスレッドで処理する接続を作って渡す
+            let (client, server) = UnixStream::pair()?;
+            thread::spawn(move || serve(server, commands));
+            Ok((
+                Encoder::new(STATUS_OK),
+                Some(File::from(OwnedFd::from(client))),
+            ))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown helper request: {}", op),
+        )),
+    }
+}
+
+/// Runs a command for the helper, writing `stdin` to its standard input.
+///
+/// If `timeout` expires, the command is killed with its process group, and the output read
+/// until then is returned with `true`.
+fn run_command(
+    program: &OsStr,
+    args: &[&OsStr],
+    stdin: Option<&[u8]>,
+    timeout: Option<Duration>,
+) -> io::Result<(Output, bool)> {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if timeout.is_some() {
+        command.process_group(0);
+    }
+    let child = command.spawn()?;
+    match PrivChild::new(child, program, timeout.is_some()).wait_with_input(stdin, timeout) {
+        Ok(output) => Ok((output, false)),
+        Err(Error::TimedOut { output, .. }) => Ok((output, true)),
+        Err(Error::Io { source, .. }) => Err(source),
+        Err(e) => Err(io::Error::other(e.to_string())),
+    }
+}
+
+/// Client side of the connection to the privileged helper.
+pub(crate) struct HelperConnection {
+    stream: UnixStream,
+    broken: bool,
+}
+
+impl HelperConnection {
+    fn new(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            broken: false,
+        }
+    }
+
+    /// Sends a request and returns the decoded body of a successful response.
+    fn request(&mut self, request: Encoder) -> Result<Vec<u8>, Error> {
+        if request.buf.len() > MAX_FRAME {
+            return Err(Error::Helper(
+                "request is too large for the helper".to_string(),
+            ));
+        }
+        let response = write_frame(&mut self.stream, &request.buf)
+            .and_then(|_| read_frame(&mut self.stream))
+            .map_err(|e| {
+                self.broken = true;
+                Error::Helper(e.to_string())
+            })?
+            .ok_or_else(|| {
+                self.broken = true;
+                Error::Helper("helper exited".to_string())
+            })?;
+
+        let invalid = |e: io::Error| Error::Helper(e.to_string());
+        let mut decoder = Decoder::new(&response);
+        match decoder.u8().map_err(invalid)? {
+            STATUS_OK => Ok(decoder.buf.to_vec()),
+            _ => {
+                let errno = decoder.i32().map_err(invalid)?;
+                let path = decoder.os_str().map_err(invalid)?;
+                let message = decoder.bytes().map_err(invalid)?;
+                if errno != 0 {
+                    Err(Error::io(path, io::Error::from_raw_os_error(errno)))
+                } else {
+                    Err(Error::Helper(String::from_utf8_lossy(message).into_owned()))
+                }
+            }
+        }
+    }
+
+    /// Reads a file.
+    pub(crate) fn read(&mut self, filename: &Path) -> Result<Vec<u8>, Error> {
+        let response =
+            self.request(Encoder::new(OP_READ).bytes(filename.as_os_str().as_bytes()))?;
+        let mut decoder = Decoder::new(&response);
+        let data = decoder.bytes().map_err(|e| Error::Helper(e.to_string()))?;
+        Ok(data.to_vec())
+    }
+
+    /// Writes or appends to a file.
+    pub(crate) fn write(
+        &mut self,
+        filename: &Path,
+        data: &[u8],
+        append: bool,
+    ) -> Result<(), Error> {
+        let op = if append { OP_APPEND } else { OP_WRITE };
+        self.request(
+            Encoder::new(op)
+                .bytes(filename.as_os_str().as_bytes())
+                .bytes(data),
+        )?;
+        Ok(())
+    }
+
+    /// Reads the metadata of a file.
+    pub(crate) fn stat(&mut self, filename: &Path) -> Result<Metadata, Error> {
+        let response =
+            self.request(Encoder::new(OP_STAT).bytes(filename.as_os_str().as_bytes()))?;
+        let mut decoder = Decoder::new(&response);
+        let decode = |decoder: &mut Decoder| -> io::Result<Metadata> {
+            Ok(Metadata {
+                mode: decoder.u32()?,
+                uid: Uid::from_raw(decoder.u32()?),
+                gid: Gid::from_raw(decoder.u32()?),
+                size: decoder.u64()?,
+                mtime: decoder.i64()?,
+            })
+        };
+        decode(&mut decoder).map_err(|e| Error::Helper(e.to_string()))
+    }
+
+    /// Opens a file and receives its file descriptor.
+    pub(crate) fn open(&mut self, filename: &Path, options: &OpenOptions) -> Result<File, Error> {
+        let (flags, mode, custom_flags) = options.encode();
+        self.request(
+            Encoder::new(OP_OPEN)
+                .bytes(filename.as_os_str().as_bytes())
+                .u8(flags)
+                .u32(mode)
+                .i32(custom_flags),
+        )?;
+        Ok(File::from(self.receive_fd()?))
+    }
+
+    /// Opens another connection to the helper, served in parallel with this one.
+    fn connect(&mut self) -> Result<HelperConnection, Error> {
+        self.request(Encoder::new(OP_CONNECT).bytes(b""))?;
+        let fd = self.receive_fd()?;
+        Ok(HelperConnection::new(UnixStream::from(fd)))
+    }
+
+    /// Receives the file descriptor sent after a successful response.
+    fn receive_fd(&mut self) -> Result<OwnedFd, Error> {
+        recv_fd(&self.stream).map_err(|e| {
+            self.broken = true;
+            Error::Helper(e.to_string())
+        })
+    }
+
+    /// Runs a command and captures its output.
+    ///
+    /// If `timeout` expires, the helper kills the command and `Error::TimedOut` is returned.
+    pub(crate) fn command(
+        &mut self,
+        program: &OsStr,
+        args: &[&OsStr],
+        stdin: Option<&[u8]>,
+        timeout: Option<Duration>,
+    ) -> Result<Output, Error> {
+        let mut request = Encoder::new(OP_COMMAND)
+            .bytes(program.as_bytes())
+            .u32(args.len() as u32);
+        for arg in args {
+            request = request.bytes(arg.as_bytes());
+        }
+        request = match stdin {
+            Some(data) => request.u8(1).bytes(data),
+            None => request.u8(0),
+        };
+        request = match timeout {
+            Some(timeout) => request
+                .u8(1)
+                .u64(timeout.as_secs())
+                .u32(timeout.subsec_nanos()),
+            None => request.u8(0),
+        };
+
+        let response = self.request(request)?;
+        let mut decoder = Decoder::new(&response);
+        let decode = |decoder: &mut Decoder| -> io::Result<(bool, Output)> {
+            Ok((
+                decoder.u8()? != 0,
+                Output {
+                    status: ExitStatus::from_raw(decoder.i32()?),
+                    stdout: decoder.bytes()?.to_vec(),
+                    stderr: decoder.bytes()?.to_vec(),
+                },
+            ))
+        };
+        match decode(&mut decoder).map_err(|e| Error::Helper(e.to_string()))? {
+            (true, output) => Err(Error::TimedOut {
+                timeout: timeout.unwrap_or_default(),
+                output,
+                kill_error: None,
+            }),
+            (false, output) => Ok(output),
+        }
+    }
+}
+
+/// The running helper process and the connections to it.
+struct RunningHelper {
+    id: usize,
+    connection: HelperConnection,
+    idle: Vec<HelperConnection>,
+    child: Child,
+}
+
+impl RunningHelper {
+    /// Closes the connections and waits for the helper to exit.
+    fn stop(self) {
+        // 最初の接続を閉じると helper は終了する
+        drop(self.connection);
+        drop(self.idle);
+        let mut child = self.child;
+        let _ = child.wait();
+    }
+}
+
+static HELPER: Mutex<Option<RunningHelper>> = Mutex::new(None);
+static HELPER_COMMANDS: AtomicBool = AtomicBool::new(false);
+static HELPER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// helper が接続してくるまで待つ時間 (command_timeout が無い場合)
+// sudo がパスワードを尋ねている間も含むので長めにする
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn helper() -> MutexGuard<'static, Option<RunningHelper>> {
+    HELPER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Allows the helper to run commands as root for `command_sudo` and the other elevated commands.
+///
+/// This is off by default, and elevated commands then start the elevator each time even
+/// while the helper runs. Because the helper is the same executable, call this before
+/// `run_helper_if_requested` so that both the helper and the caller see the setting.
+pub fn set_helper_commands(allow: bool) {
+    HELPER_COMMANDS.store(allow, Ordering::SeqCst);
+}
+
+/// Checks if the helper is allowed to run commands.
+pub fn helper_commands() -> bool {
+    HELPER_COMMANDS.load(Ordering::SeqCst)
+}
+
+/// Runs `f` with a connection to the helper, or returns `None` if it is not running.
+///
+/// The lock is held only while taking a connection, so slow requests such as commands do
+/// not block the requests of other threads.
+pub(crate) fn with_helper<T>(
+    f: impl FnOnce(&mut HelperConnection) -> Result<T, Error>,
+) -> Option<Result<T, Error>> {
+    let (id, mut connection) = {
+        let mut helper = helper();
+        let running = helper.as_mut()?;
+        let connection = match running.idle.pop() {
+            Some(connection) => Ok(connection),
+            None => running.connection.connect(),
+        };
+        match connection {
+            Ok(connection) => (running.id, connection),
+            Err(e) => {
+                if running.connection.broken {
+                    stop_running(&mut helper);
+                }
+                return Some(Err(e));
+            }
+        }
+    };
+    let result = f(&mut connection);
+
+    // 使い終わった接続は次の要求で使う (途中で helper が入れ替わっていれば捨てる)
+    let mut helper = helper();
+    match helper.as_mut() {
+        Some(running) if running.id == id => {
+            if connection.broken {
+                // 通信できなくなった helper は片付けて、以降は通常の sudo に戻す
+                stop_running(&mut helper);
+            } else {
+                running.idle.push(connection);
+            }
+        }
+        _ => {}
+    }
+    Some(result)
+}
+
+/// Stops the helper held by `helper`.
+fn stop_running(helper: &mut Option<RunningHelper>) {
+    if let Some(running) = helper.take() {
+        running.stop();
+    }
+}
+
+/// Starts the privileged helper.
+///
+/// The current executable is run through the elevator with `HELPER_ARG`, so `main` must call
+/// `run_helper_if_requested` first, and `Error::Helper` is returned if it has not been called.
+/// While the helper runs, the `*_sudo` functions send their
+/// requests to it instead of starting a new `sudo` each time. The helper exits when
+/// `stop_helper` is called or this process exits.
+///
+/// Note that an executable calling `run_helper_if_requested` can be started as the helper by
+/// anyone who can run it as root, for example through a sudoers rule for this executable
+/// alone, and it then reads, writes and opens any file as root on request. The helper only
+/// serves the process that started it (checked with `SO_PEERCRED`), and runs commands only if
+/// `set_helper_commands` is enabled, but do not call `run_helper_if_requested` in executables
+/// that are allowed to run as root without a password for other purposes.
+pub fn start_helper() -> Result<(), Error> {
+    let mut helper = helper();
+    if helper.is_some() {
+        return Ok(());
+    }
+
+    *helper = Some(launch_helper()?);
+    Ok(())
+}
+
+/// Runs `f` with the running helper, or with a helper started only for this call.
+///
+/// This is for requests that cannot be done with `sudo` alone, such as passing a file descriptor.
+/// As `start_helper`, this fails unless `run_helper_if_requested` has been called.
+pub(crate) fn with_helper_or_launch<T>(
+    f: impl Fn(&mut HelperConnection) -> Result<T, Error>,
+) -> Result<T, Error> {
+    if let Some(result) = with_helper(&f) {
+        return result;
+    }
+    let mut running = launch_helper()?;
+    let result = f(&mut running.connection);
+    running.stop();
+    result
+}
+
+/// Starts the helper process and waits for it to connect.
+fn launch_helper() -> Result<RunningHelper, Error> {
+    // main で run_helper_if_requested を呼んでいないと、自分自身がもう1つ root で動いてしまう
+    if !HELPER_ENABLED.load(Ordering::SeqCst) {
+        return Err(Error::Helper(
+            "helper not enabled (call run_helper_if_requested at the beginning of main)"
+                .to_string(),
+        ));
+    }
+
+    // 他のユーザーから接続されないように自分だけが入れるディレクトリにソケットを作る
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let dir = env::temp_dir().join(format!(
+        "jelly_uidmng_helper_{}_{}",
+        std::process::id(),
+        count
+    ));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| Error::io(&dir, e))?;
+    let result = spawn_helper(&dir.join("socket"));
+    let _ = fs::remove_dir_all(&dir);
+    let (connection, child) = result?;
+    Ok(RunningHelper {
+        id: count,
+        connection,
+        idle: Vec::new(),
+        child,
+    })
+}
+
+/// Spawns the helper and waits for it to connect to `socket`.
+///
+/// The helper is killed if it does not connect within `command_timeout` (or a minute).
+fn spawn_helper(socket: &Path) -> Result<(HelperConnection, Child), Error> {
+    let listener = UnixListener::bind(socket).map_err(|e| Error::io(socket, e))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| Error::io(socket, e))?;
+
+    let exe = env::current_exe().map_err(|e| Error::io("/proc/self/exe", e))?;
+    let elevator = elevator();
+    let prompt = password_prompt();
+    let args = [OsStr::new(HELPER_ARG), socket.as_os_str()];
+    let mut command = elevator::elevated_command(&elevator, &prompt, exe.as_os_str(), &args)?;
+    // 接続されなかった時にまとめて kill できるようにプロセスグループを分ける
+    if prompt.own_group() {
+        command.process_group(0);
+    }
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| Error::spawn(command.get_program(), e))?;
+
+    // helper から接続されるのを待つ (sudo がパスワードを尋ねている間も待つが、時間は限る)
+    let timeout = command_timeout().unwrap_or(CONNECT_TIMEOUT);
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream
+                    .set_nonblocking(false)
+                    .map_err(|e| Error::io(socket, e))?;
+                return Ok((HelperConnection::new(stream), child));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Ok(Some(status)) = child.try_wait() {
+                    return Err(Error::Helper(format!(
+                        "helper exited before connecting ({})",
+                        status
+                    )));
+                }
+                if Instant::now() >= deadline {
+                    let mut child = PrivChild::elevated(child, exe.as_os_str(), &elevator, &prompt);
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(Error::Helper(format!(
+                        "helper did not connect within {:?}",
+                        timeout
+                    )));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::io(socket, e));
+            }
+        }
+    }
+}
+
+/// Stops the privileged helper if it is running.
+pub fn stop_helper() {
+    if let Some(running) = helper().take() {
+        running.stop();
+    }
+}
+
+/// Checks if the privileged helper is running.
+pub fn helper_running() -> bool {
+    helper().is_some()
+}
+
+/// Checks that the peer of the helper connection is the process that started the helper.
+///
+/// The peer must be this process or one of its ancestors, running as root or as the user
+/// that started the elevator.
+fn check_peer(stream: &UnixStream) -> io::Result<()> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let uid = Uid::from_raw(cred.uid);
+    let user = uid.is_root() || target_user().is_ok_and(|user| user.uid == uid);
+
+    // sudo などを挟むので親だけでなく祖先までたどる
+    let mut pid = std::process::id();
+    let mut ancestor = pid == cred.pid as u32;
+    while !ancestor && pid > 1 {
+        match proc_stat(pid) {
+            Some((ppid, _)) => pid = ppid,
+            None => break,
+        }
+        ancestor = pid == cred.pid as u32;
+    }
+
+    if user && ancestor {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the peer is not the process that started the helper",
+        ))
+    }
+}
+
+/// Runs as the privileged helper and exits if the process was started by `start_helper`.
+///
+/// Call this at the beginning of `main` before doing anything else, after
+/// `set_helper_commands` if commands are to be run by the helper.
+pub fn run_helper_if_requested() {
+    let mut args = env::args_os().skip(1);
+    if args.next().as_deref() != Some(OsStr::new(HELPER_ARG)) {
+        // helper として起動されても動けるので、helper を起動してよい
+        HELPER_ENABLED.store(true, Ordering::SeqCst);
+        return;
+    }
+
+    let socket: Option<OsString> = args.next();
+    let serve_socket = |socket| {
+        let stream = UnixStream::connect(socket)?;
+        check_peer(&stream)?;
+        serve(stream, helper_commands())
+    };
+    let code = match socket.map(serve_socket) {
+        Some(Ok(())) => 0,
+        Some(Err(e)) => {
+            eprintln!("jelly-uidmng helper: {}", e);
+            1
+        }
+        None => 2,
+    };
+    std::process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Seek;
+
+    #[test]
+    fn test_helper_protocol() -> Result<(), Box<dyn std::error::Error>> {
+        // helper の処理を別スレッドで動かして通信する
+        let (client, server) = UnixStream::pair()?;
+        let handle = thread::spawn(move || serve(server, true));
+        let mut connection = HelperConnection::new(client);
+
+        let file_name = format!("/tmp/test_helper_{}.txt", std::process::id());
+        let file_name = Path::new(&file_name);
+        connection.write(file_name, b"Hello", false)?;
+        connection.write(file_name, b", World!\n", true)?;
+        assert_eq!(connection.read(file_name)?, b"Hello, World!\n");
+
+        let metadata = connection.stat(file_name)?;
+        assert!(metadata.is_file());
+        assert_eq!(metadata.size, 14);
+        assert_eq!(metadata.uid, Uid::effective());
+
+        // 開いたファイルの fd を受け取る
+        let mut options = OpenOptions::new();
+        let mut file = connection.open(file_name, options.read(true).append(true))?;
+        file.write_all(b"appended\n")?;
+        file.seek(io::SeekFrom::Start(0))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        assert_eq!(data, b"Hello, World!\nappended\n");
+        drop(file);
+
+        let args = [OsStr::new("-c"), OsStr::new("cat; echo err >&2; exit 3")];
+        let output = connection.command(OsStr::new("sh"), &args, Some(b"input"), None)?;
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"input");
+        assert_eq!(output.stderr, b"err\n");
+
+        // 時間切れのコマンドは helper がプロセスグループごと kill する
+        let start = std::time::Instant::now();
+        let args = [
+            OsStr::new("-c"),
+            OsStr::new("sleep 10 & echo started; wait"),
+        ];
+        let timeout = Some(Duration::from_millis(200));
+        match connection.command(OsStr::new("sh"), &args, None, timeout) {
+            Err(Error::TimedOut {
+                timeout, output, ..
+            }) => {
+                assert_eq!(timeout, Duration::from_millis(200));
+                assert_eq!(output.stdout, b"started\n");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        std::fs::remove_file(file_name)?;
+        match connection.read(file_name) {
+            Err(Error::Io { path, source }) => {
+                assert_eq!(path, file_name);
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // 接続を閉じると helper は終了する
+        drop(connection);
+        handle.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_helper_not_enabled() {
+        // run_helper_if_requested を呼んでいなければテストの実行ファイルを root で起動しない
+        assert!(matches!(start_helper(), Err(Error::Helper(_))));
+        assert!(!helper_running());
+        let result = with_helper_or_launch(|helper| helper.read(Path::new("/etc/hostname")));
+        assert!(matches!(result, Err(Error::Helper(_))));
+    }
+
+    #[test]
+    fn test_helper_connections() -> Result<(), Box<dyn std::error::Error>> {
+        let (client, server) = UnixStream::pair()?;
+        check_peer(&server)?;
+        let handle = thread::spawn(move || serve(server, false));
+        let mut connection = HelperConnection::new(client);
+
+        // 許可されていなければコマンドは実行しない
+        let result = connection.command(OsStr::new("true"), &[], None, None);
+        assert!(matches!(result, Err(Error::Helper(_))));
+        assert!(!connection.broken);
+
+        // 別の接続の要求は並行して処理される
+        let mut slow = connection.connect()?;
+        let file_name = format!("/tmp/test_helper_connections_{}.txt", std::process::id());
+        let file_name = Path::new(&file_name);
+        let fifo = file_name.with_extension("fifo");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU)?;
+        let reader = {
+            let fifo = fifo.clone();
+            thread::spawn(move || slow.read(&fifo))
+        };
+        connection.write(file_name, b"data", false)?;
+        assert_eq!(connection.read(file_name)?, b"data");
+        std::fs::write(&fifo, b"fifo")?;
+        assert_eq!(reader.join().unwrap()?, b"fifo");
+
+        std::fs::remove_file(file_name)?;
+        std::fs::remove_file(&fifo)?;
+        drop(connection);
+        handle.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -1,14 +1,97 @@
-use nix::unistd::{setegid, seteuid, Gid, Uid};
-use std::env;
-use std::error::Error;
-use std::ffi::OsStr;
+use nix::unistd::{getgroups, initgroups, setegid, seteuid, setgroups, Gid, Uid};
+use std::ffi::{CString, OsStr, OsString};
 use std::io::Write;
-use std::process::{Command, Output, Stdio};
+use std::path::Path;
+use std::process::Output;
 use std::result::Result;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+mod atomic;
+mod attr;
+mod caps;
+mod child;
+mod command;
+mod elevator;
+mod environ;
+mod error;
+mod fsops;
+mod giveback;
+mod guard;
+mod helper;
+mod lock;
+mod metadata;
+mod mode;
+mod offset;
+mod open;
+mod options;
+mod user;
+mod value;
+pub use atomic::{
+    write_atomic, write_atomic_root, write_atomic_sudo, write_atomic_try, write_atomic_user,
+};
+pub use attr::{write_attr, write_attr_root, write_attr_sudo, write_attr_try, write_attr_user};
+pub use caps::{command_with_caps, with_capabilities, Cap};
+pub use child::{spawn, spawn_root, spawn_sudo, spawn_try, spawn_user, PrivChild};
+pub use command::{
+    command_root_with_input, command_root_with_reader, command_sudo_with_input,
+    command_sudo_with_reader, command_timeout, command_try_with_input, command_try_with_reader,
+    command_user_with_input, command_user_with_reader, command_with_input, command_with_reader,
+    set_command_timeout, PrivCommand,
+};
+pub use elevator::{
+    elevator, password_prompt, set_elevator, set_elevator_kind, set_password_prompt, Doas,
+    Elevator, ElevatorKind, PasswordPrompt, Pkexec, Su, Sudo,
+};
+pub use environ::{
+    root_env, set_root_env, set_user_env, user_env, RootEnv, UserEnv, DEFAULT_ROOT_ENV,
+};
+pub use error::Error;
+pub use fsops::{
+    chmod, chmod_root, chmod_sudo, chmod_try, chmod_user, chown, chown_root, chown_sudo, chown_try,
+    chown_user, copy, copy_root, copy_sudo, copy_try, copy_user, create_dir_all,
+    create_dir_all_root, create_dir_all_sudo, create_dir_all_try, create_dir_all_user, mknod,
+    mknod_root, mknod_sudo, remove_dir_all, remove_dir_all_root, remove_dir_all_sudo,
+    remove_dir_all_try, remove_dir_all_user, remove_file, remove_file_root, remove_file_sudo,
+    remove_file_try, remove_file_user, rename, rename_root, rename_sudo, rename_try, rename_user,
+    symlink, symlink_root, symlink_sudo, symlink_try, symlink_user, NodeKind,
+};
+pub use giveback::{
+    created_paths, give_back, give_back_all, set_track_created, track_created, with_track_created,
+    GiveBackGuard,
+};
+pub use guard::{RootGuard, UserGuard};
+pub use helper::{
+    helper_commands, helper_running, run_helper_if_requested, set_helper_commands, start_helper,
+    stop_helper, HELPER_ARG,
+};
+pub use lock::PrivilegeLock;
+pub use metadata::{
+    exists, exists_root, exists_sudo, exists_try, exists_user, metadata, metadata_root,
+    metadata_sudo, metadata_try, metadata_user, Metadata,
+};
+pub use mode::Mode;
+pub use nix::sys::signal::Signal;
+pub use offset::{
+    read_at, read_at_root, read_at_sudo, read_at_try, read_at_user, write_at, write_at_root,
+    write_at_sudo, write_at_try, write_at_user,
+};
+pub use open::{open, open_root, open_sudo, open_try, open_user, OpenOptions};
+pub use options::{write_with, WriteOptions};
+pub use user::{
+    clear_target_user, set_target_user, set_user_resolver, target_user, TargetUser, UserResolver,
+    TARGET_USER_ENV,
+};
+pub use value::{
+    read_value, read_value_root, read_value_sudo, read_value_try, read_value_user, write_value,
+    write_value_root, write_value_sudo, write_value_try, write_value_user,
+};
 
 static ALLOW_SUDO: AtomicBool = AtomicBool::new(false);
 
+// user に移行する前の root の supplementary group
+static ROOT_GROUPS: Mutex<Option<Vec<Gid>>> = Mutex::new(None);
+
 /// Sets whether the use of sudo is allowed.
 pub fn set_allow_sudo(value: bool) {
     ALLOW_SUDO.store(value, Ordering::SeqCst);
@@ -30,10 +113,12 @@ pub fn has_root() -> bool {
 }
 
 /// Changes to root.
-pub fn change_root() -> Result<(), Box<dyn Error>> {
+pub fn change_root() -> Result<(), Error> {
+    let _lock = PrivilegeLock::acquire();
+
     // root 権限を保有していないと変更できない
     if !has_root() {
-        return Err("don't have root permission".into());
+        return Err(Error::NoRootPermission);
     }
 
     // 既に euid が root である場合は何もしない
@@ -45,173 +130,189 @@ pub fn change_root() -> Result<(), Box<dyn Error>> {
     seteuid(Uid::from_raw(0))?;
     setegid(Gid::from_raw(0))?;
 
+    // user に移行した時に保存した root の supplementary group に戻す
+    if let Some(groups) = ROOT_GROUPS.lock().unwrap().take() {
+        setgroups(&groups)?;
+    }
+
     Ok(())
 }
 
 /// Changes to user.
-pub fn change_user() -> Result<(), Box<dyn Error>> {
+pub fn change_user() -> Result<(), Error> {
+    let _lock = PrivilegeLock::acquire();
+
     // 既に euid が 非root である場合は何もしない
     if !is_root() {
         return Ok(());
     }
 
-    // "SUDO_UID" と "SUDO_GID" が設定されていない場合はエラー
-    let sudo_uid = env::var("SUDO_UID")?;
-    let uid = sudo_uid.parse::<u32>()?;
-    let uid = Uid::from_raw(uid);
-    let sudo_gid = env::var("SUDO_GID")?;
-    let gid = sudo_gid.parse::<u32>()?;
-    let gid = Gid::from_raw(gid);
-
-    // SUDO_UID が 既に root の場合は変更できない
-    if uid.is_root() {
-        return Err("Invalid SUDO_UID".into());
-    }
+    // 移行先のユーザーが決められない場合はエラー
+    let user = target_user()?;
+
+    // root の supplementary group を保存してから user のものに切り替える
+    let root_groups = getgroups()?;
+    set_user_groups(&user)?;
+    *ROOT_GROUPS.lock().unwrap() = Some(root_groups);
+
+    setegid(user.gid)?;
+    seteuid(user.uid)?;
+
+    Ok(())
+}
 
-    setegid(gid)?;
-    seteuid(uid)?;
+/// Applies the supplementary groups of the invoking user.
+fn set_user_groups(user: &TargetUser) -> Result<(), Error> {
+    // ユーザー名が分かればそのユーザーの所属グループを設定する
+    if let Some(name) = &user.name {
+        if let Ok(name) = CString::new(name.as_str()) {
+            initgroups(&name, user.gid)?;
+            return Ok(());
+        }
+    }
 
+    // 分からない場合は root のグループが残らないように primary group のみにする
+    setgroups(&[user.gid])?;
     Ok(())
 }
 
+/// Runs `f` with user permissions.
+fn with_user<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    // 実行中に他のスレッドが権限を変更しないようにする
+    let _lock = PrivilegeLock::acquire();
+
+    // root でなければそのまま実行
+    if !is_root() {
+        return f();
+    }
+
+    // user に移行して実行し、guard の drop で元に戻す
+    let _guard = UserGuard::new()?;
+    f()
+}
+
+/// Runs `f` with root permissions, or `sudo` if root is not available and sudo is allowed.
+fn with_root<T>(
+    f: impl FnOnce() -> Result<T, Error>,
+    sudo: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    // 実行中に他のスレッドが権限を変更しないようにする
+    let lock = PrivilegeLock::acquire();
+
+    if is_root() {
+        // root であればそのまま実行
+        f()
+    } else if has_root() {
+        // root に移行して実行し、guard の drop で元に戻す
+        let _guard = RootGuard::new()?;
+        f()
+    } else if allow_sudo() {
+        // root に変更できない場合は sudo で実行 (権限は変更しないのでロックは不要)
+        drop(lock);
+        sudo()
+    } else {
+        Err(Error::SudoNotAllowed)
+    }
+}
+
 /// Executes a command with the given program and arguments.
-pub fn command<I, S>(program: S, args: I) -> Result<Output, Box<dyn Error>>
+pub fn command<I, S>(program: S, args: I) -> Result<Output, Error>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
     // コマンド実行して結果を返す
-    Ok(Command::new(program).args(args).output()?)
+    PrivCommand::new(program).args(args).output()
 }
 
-/// Executes a command with `sudo` using the given program and arguments.
-pub fn command_sudo<I, S>(program: S, args: I) -> Result<Output, Box<dyn Error>>
+/// Executes a command with `sudo` (or the configured elevator) using the given program and arguments.
+pub fn command_sudo<I, S>(program: S, args: I) -> Result<Output, Error>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let mut command_args: Vec<S> = vec![program];
-    command_args.extend(args);
-    Ok(Command::new("sudo").args(command_args).output()?)
+    // sudo などの elevator を経由して実行する
+    PrivCommand::new(program)
+        .args(args)
+        .mode(Mode::Sudo)
+        .output()
 }
 
 /// Executes a command in user mode.
-pub fn command_user<I, S>(program: S, args: I) -> Result<Output, Box<dyn Error>>
+pub fn command_user<I, S>(program: S, args: I) -> Result<Output, Error>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    if !is_root() {
-        // root でなければそのまま実行
-        command(program, args)
-    } else {
-        // userに移行して実行
-        change_user()?;
-        let result = command(program, args);
-        change_root()?;
-        result
-    }
+    PrivCommand::new(program)
+        .args(args)
+        .mode(Mode::User)
+        .output()
 }
 
 /// Executes a command with root privileges.
-pub fn command_root<I, S>(program: S, args: I) -> Result<Output, Box<dyn Error>>
+pub fn command_root<I, S>(program: S, args: I) -> Result<Output, Error>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    if is_root() {
-        // root であればそのまま実行
-        command(program, args)
-    } else {
-        // userモードの場合
-        if change_root().is_ok() {
-            // root に変更できた場合はそのまま実行してuserモードに戻す
-            let result = command(program, args);
-            change_user()?;
-            result
-        } else {
-            if allow_sudo() {
-                // root に変更できない場合は sudo で実行
-                command_sudo(program, args)
-            } else {
-                Err("don't have root permission".into())
-            }
-        }
-    }
+    PrivCommand::new(program)
+        .args(args)
+        .mode(Mode::Root)
+        .output()
 }
 
 /// Executes a command and tries to use root permissions if the initial execution fails.
-pub fn command_try<I, S>(program: S, args: I) -> Result<Output, Box<dyn Error>>
+pub fn command_try<I, S>(program: S, args: I) -> Result<Output, Error>
 where
     I: IntoIterator<Item = S> + Clone,
     S: AsRef<OsStr> + Clone,
 {
-    let result = command(program.clone(), args.clone());
-    if let Ok(output) = &result {
-        if output.status.success() {
-            return result;
-        }
-    }
-
-    if !is_root() {
-        command_root(program, args)
-    } else {
-        result
-    }
+    PrivCommand::new(program)
+        .args(args)
+        .mode(Mode::Try)
+        .output()
 }
 
 /// Reads binary data from a file.
-pub fn read(filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    let data = std::fs::read(filename)?;
+pub fn read(filename: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    let data = std::fs::read(filename).map_err(|e| Error::io(filename, e))?;
     Ok(data)
 }
 
 /// Reads binary data from a file using user permissions.
-pub fn read_sudo(filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn read_sudo(filename: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    // helper が起動していれば helper で読み込む
+    if let Some(result) = helper::with_helper(|helper| helper.read(filename)) {
+        return result;
+    }
+
     // `cat` コマンドを使ってファイルを読み込む
-    let output = command_sudo("cat", [filename])?;
+    let output = command_sudo(OsStr::new("cat"), [filename.as_os_str()])?;
     if output.status.success() {
         Ok(output.stdout) // 成功時はデータを返す
     } else {
-        Err(format!("Failed to read from file: {}", filename).into()) // エラー時はエラーメッセージを返す
+        Err(Error::sudo_failed(&output)) // エラー時は終了ステータスと標準エラー出力を返す
     }
 }
 
 /// Reads binary data from a file using user permissions.
-pub fn read_user(filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    if !is_root() {
-        read(filename)
-    } else {
-        change_user()?;
-        let result = read(filename);
-        change_root()?;
-        result
-    }
+pub fn read_user(filename: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    with_user(|| read(filename))
 }
 
 /// Reads binary data from a file using `sudo` permissions.
-pub fn read_root(filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    if is_root() {
-        // root であればそのまま読み込む
-        read(filename)
-    } else {
-        if has_root() {
-            change_root()?;
-            let result = read(filename);
-            change_root()?;
-            result
-        } else {
-            if allow_sudo() {
-                read_sudo(filename)
-            } else {
-                Err("don't have root permission".into())
-            }
-        }
-    }
+pub fn read_root(filename: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    with_root(|| read(filename), || read_sudo(filename))
 }
 
 /// Reads binary data from a file and tries to use root permissions if the initial read fails.
-pub fn read_try(filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn read_try(filename: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
     let result = read(filename);
     if result.is_err() && !is_root() {
         read_root(filename)
@@ -221,144 +322,125 @@ pub fn read_try(filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
 }
 
 /// Writes binary data to a file.
-pub fn write(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    std::fs::write(filename, data)?;
+pub fn write(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    std::fs::write(filename, data).map_err(|e| Error::io(filename, e))?;
     Ok(())
 }
 
-/// Writes binary data to a file using `sudo` permissions.
-pub fn write_sudo(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    // 標準入力を `cat` に渡してファイルに書き込む
-    let mut child = Command::new("sudo")
-        .arg("sh")
-        .arg("-c")
-        .arg(format!("cat > {}", filename))
-        .stdin(Stdio::piped())
-        .spawn()?;
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin.write_all(data)?; // データを書き込む
+/// Writes `data` to a file with `tee`, or appends it with `dd`, run through `elevator`.
+///
+/// The filename is passed as its own argument and never reaches a shell. As with `append`,
+/// a missing file is not created when appending.
+fn write_elevated(
+    elevator: &Arc<dyn Elevator>,
+    filename: &Path,
+    data: &[u8],
+    append: bool,
+) -> Result<(), Error> {
+    let (program, args) = if append {
+        // tee -a はファイルを作ってしまうので dd で追記する
+        let mut of = OsString::from("of=");
+        of.push(filename);
+        let args = vec![
+            of,
+            OsString::from("oflag=append"),
+            OsString::from("conv=notrunc,nocreat"),
+            OsString::from("status=none"),
+        ];
+        ("dd", args)
     } else {
-        return Err("Failed to write to file".into());
-    }
-
-    // プロセスが終了するのを待つ
-    let status = child.wait()?;
-    if status.success() {
+        let args = vec![OsString::from("--"), filename.as_os_str().to_os_string()];
+        ("tee", args)
+    };
+    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+
+    // 標準入力を渡してファイルに書き込む
+    let prompt = password_prompt();
+    let output =
+        elevator::run_elevated_with(elevator, &prompt, OsStr::new(program), &args, Some(data))?;
+    if output.status.success() {
         Ok(()) // 成功時は Ok を返す
     } else {
-        Err(format!("Failed to write to file: {}", filename).into()) // エラー時はエラーメッセージを返す
+        Err(Error::sudo_failed(&output)) // エラー時は終了ステータスと標準エラー出力を返す
     }
 }
 
+/// Writes binary data to a file using `sudo` permissions.
+pub fn write_sudo(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    giveback::track(filename, || {
+        // helper が起動していれば helper で書き込む
+        if let Some(result) = helper::with_helper(|helper| helper.write(filename, data, false)) {
+            return result;
+        }
+        write_elevated(&elevator(), filename, data, false)
+    })
+}
+
 /// Writes binary data to a file using user permissions.
-pub fn write_user(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    if !is_root() {
-        write(filename, data)
-    } else {
-        change_user()?;
-        let result = write(filename, data);
-        change_root()?;
-        result
-    }
+pub fn write_user(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_user(|| write(filename, data))
 }
 
 /// Writes binary data to a file using `sudo` permissions.
-pub fn write_root(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    if is_root() {
-        // root であればそのまま書き込む
-        write(filename, data)
-    } else {
-        if has_root() {
-            change_root()?;
-            let result = write(filename, data);
-            change_root()?;
-            result
-        } else {
-            if allow_sudo() {
-                write_sudo(filename, data)
-            } else {
-                Err("don't have root permission".into())
-            }
-        }
-    }
+pub fn write_root(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    giveback::track(filename, || {
+        with_root(|| write(filename, data), || write_sudo(filename, data))
+    })
 }
 
 /// Writes binary data to a file and tries to use root permissions if the initial write fails.
-pub fn write_try(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    let result = write(filename, data);
-    if result.is_err() && !is_root() {
-        write_root(filename, data)
-    } else {
-        result
-    }
+pub fn write_try(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    giveback::track(filename, || {
+        let result = write(filename, data);
+        if result.is_err() && !is_root() {
+            write_root(filename, data)
+        } else {
+            result
+        }
+    })
 }
 
 /// Append binary data to a file.
-pub fn append(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    let mut file = std::fs::OpenOptions::new().append(true).open(filename)?;
-    file.write_all(data)?;
+pub fn append(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(filename)
+        .map_err(|e| Error::io(filename, e))?;
+    file.write_all(data).map_err(|e| Error::io(filename, e))?;
     Ok(())
 }
 
 /// Append binary data to a file using `sudo` permissions.
-pub fn append_sudo(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    // 標準入力を `cat` に渡してファイルに書き込む
-    let mut child = Command::new("sudo")
-        .arg("sh")
-        .arg("-c")
-        .arg(format!("cat >> {}", filename))
-        .stdin(Stdio::piped())
-        .spawn()?;
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin.write_all(data)?; // データを書き込む
-    } else {
-        return Err("Failed to append to file".into());
-    }
-
-    // プロセスが終了するのを待つ
-    let status = child.wait()?;
-    if status.success() {
-        Ok(()) // 成功時は Ok を返す
-    } else {
-        Err(format!("Failed to write to file: {}", filename).into()) // エラー時はエラーメッセージを返す
+pub fn append_sudo(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    // helper が起動していれば helper で書き込む
+    if let Some(result) = helper::with_helper(|helper| helper.write(filename, data, true)) {
+        return result;
     }
+    write_elevated(&elevator(), filename, data, true)
 }
 
 /// Append binary data to a file using user permissions.
-pub fn append_user(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    if !is_root() {
-        append(filename, data)
-    } else {
-        change_user()?;
-        let result = append(filename, data);
-        change_root()?;
-        result
-    }
+pub fn append_user(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_user(|| append(filename, data))
 }
 
 /// Append binary data to a file using `sudo` permissions.
-pub fn append_root(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
-    if is_root() {
-        // root であればそのまま書き込む
-        append(filename, data)
-    } else {
-        if has_root() {
-            change_root()?;
-            let result = write(filename, data);
-            change_root()?;
-            result
-        } else {
-            if allow_sudo() {
-                append_sudo(filename, data)
-            } else {
-                Err("don't have root permission".into())
-            }
-        }
-    }
+pub fn append_root(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_root(|| append(filename, data), || append_sudo(filename, data))
 }
 
 /// Append binary data to a file and tries to use root permissions if the initial write fails.
-pub fn append_try(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+pub fn append_try(filename: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
     let result = append(filename, data);
     if result.is_err() && !is_root() {
         append_root(filename, data)
@@ -372,6 +454,7 @@ mod tests {
     use super::*;
     use nix::sys::stat::stat;
     use std::error::Error;
+    use std::os::unix::ffi::OsStrExt;
     use std::process::Output;
 
     fn assert_file_permission(file: &str, root: bool) {
@@ -387,6 +470,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_return)] // 元のテストのまま残す
     fn test_command() -> Result<(), Box<dyn Error>> {
         if !is_root() {
             set_allow_sudo(true);
@@ -406,6 +490,122 @@ mod tests {
         return Ok(());
     }
 
+    #[test]
+    fn test_guard() -> Result<(), Box<dyn Error>> {
+        if has_root() {
+            // 確認中に他のテストが権限を変更しないようにする
+            let _lock = PrivilegeLock::acquire();
+            let euid = Uid::effective();
+            let egid = Gid::effective();
+
+            // panic しても guard の drop で元に戻る
+            let result = std::panic::catch_unwind(|| {
+                let _guard = UserGuard::new().unwrap();
+                assert!(!is_root());
+                panic!("panic in user mode");
+            });
+            assert!(result.is_err());
+            assert_eq!(Uid::effective(), euid);
+            assert_eq!(Gid::effective(), egid);
+
+            // 入れ子にしても元に戻る
+            {
+                let _user = UserGuard::new()?;
+                assert!(!is_root());
+                {
+                    let _root = RootGuard::new()?;
+                    assert!(is_root());
+                }
+                assert!(!is_root());
+            }
+            assert_eq!(Uid::effective(), euid);
+            assert_eq!(Gid::effective(), egid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_error() -> Result<(), Box<dyn Error>> {
+        let file_name = "/tmp/test_error_not_exist/file.txt";
+        match read(file_name) {
+            Err(crate::Error::Io { path, source }) => {
+                assert_eq!(path, std::path::Path::new(file_name));
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        if !has_root() {
+            assert!(matches!(change_root(), Err(crate::Error::NoRootPermission)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_groups() -> Result<(), Box<dyn Error>> {
+        if has_root() {
+            // 確認中に他のテストが権限を変更しないようにする
+            let _lock = PrivilegeLock::acquire();
+            let root_groups = getgroups()?;
+            {
+                let _guard = UserGuard::new()?;
+                let groups = getgroups()?;
+                assert!(groups.contains(&Gid::effective()));
+                assert!(!groups.contains(&Gid::from_raw(0)));
+            }
+            assert_eq!(getgroups()?, root_groups);
+
+            // change_user の後に guard で root になっても、change_root で root のグループに戻る
+            change_user()?;
+            let user_groups = getgroups()?;
+            {
+                let _guard = RootGuard::new()?;
+                assert_eq!(getgroups()?, root_groups);
+            }
+            assert_eq!(getgroups()?, user_groups);
+            change_root()?;
+            assert_eq!(getgroups()?, root_groups);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_privilege_lock() -> Result<(), Box<dyn Error>> {
+        // 同じスレッドからは再入できる
+        {
+            let _lock0 = PrivilegeLock::acquire();
+            let _lock1 = PrivilegeLock::acquire();
+            let _guard = RootGuard::new();
+        }
+
+        if has_root() {
+            // user と root の操作を並行して実行しても権限が混ざらない
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    std::thread::spawn(move || -> Result<(), crate::Error> {
+                        for j in 0..20 {
+                            let file_name = format!("/tmp/test_privilege_lock_{}_{}.txt", i, j);
+                            if i % 2 == 0 {
+                                write_user(&file_name, b"user")?;
+                                assert_file_permission(&file_name, false);
+                                command_user("rm", [file_name.as_str()])?;
+                            } else {
+                                write_root(&file_name, b"root")?;
+                                assert_file_permission(&file_name, true);
+                                command_root("rm", [file_name.as_str()])?;
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap()?;
+            }
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_command_sudo() -> Result<(), Box<dyn Error>> {
         let output: Output = command_sudo("echo", ["Hello, world!"])?;
@@ -414,6 +614,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow, clippy::needless_borrows_for_generic_args)] // 元のテストのまま残す
     fn test_write_user() -> Result<(), Box<dyn Error>> {
         let file_name = "/tmp/test_write_user.txt";
         let write_data = vec![
@@ -430,6 +631,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow, clippy::needless_borrows_for_generic_args)] // 元のテストのまま残す
     fn test_write_root() -> Result<(), Box<dyn Error>> {
         set_allow_sudo(true);
 
@@ -466,6 +668,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow, clippy::needless_borrows_for_generic_args)] // 元のテストのまま残す
     fn test_append_root() -> Result<(), Box<dyn Error>> {
         set_allow_sudo(true);
 
@@ -494,7 +697,95 @@ mod tests {
         Ok(())
     }
 
+    /// Writes a shell script that stands in for `sudo`.
+    pub(crate) fn write_shim(name: &str, script: &str) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let path =
+            std::env::temp_dir().join(format!("jelly_uidmng_{}_{}", name, std::process::id()));
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_write_sudo_hostile_filename() -> Result<(), Box<dyn Error>> {
+        // 引数をそのまま実行するだけの sudo の代わり
+        let shim = write_shim(
+            "sudo_passthrough",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+
+        let dir = std::env::temp_dir().join(format!("jelly_uidmng_hostile_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let names = [
+            OsStr::new("a b.txt"),
+            OsStr::new("quote'\"s.txt"),
+            OsStr::new("semi;touch INJECTED;.txt"),
+            OsStr::new("$(touch INJECTED).txt"),
+            OsStr::new("`touch INJECTED`.txt"),
+            OsStr::new("-n.txt"),
+            OsStr::new("redirect>INJECTED"),
+            OsStr::from_bytes(b"non-utf8-\xff.txt"),
+        ];
+        for name in names {
+            let file_name = dir.join(name);
+            write_elevated(&elevator, &file_name, b"Hello", false)?;
+            write_elevated(&elevator, &file_name, b", World!", true)?;
+            assert_eq!(read(&file_name)?, b"Hello, World!");
+        }
+
+        // ファイル名がシェルに解釈されていなければ INJECTED は作られない
+        assert!(!dir.join("INJECTED").exists());
+        assert!(!std::path::Path::new("INJECTED").exists());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), names.len());
+
+        std::fs::remove_dir_all(&dir)?;
+        std::fs::remove_file(&shim)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_missing() -> Result<(), Box<dyn Error>> {
+        let shim = write_shim(
+            "sudo_append_missing",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let file_name =
+            std::env::temp_dir().join(format!("test_append_missing_{}", std::process::id()));
+
+        // 直接でも sudo でも存在しないファイルには追記しない
+        match append(&file_name, b"data") {
+            Err(crate::Error::Io { source, .. }) => {
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound)
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let result = write_elevated(&elevator, &file_name, b"data", true);
+        assert!(matches!(result, Err(crate::Error::SudoFailed { .. })));
+        assert!(!file_name.exists());
+
+        std::fs::remove_file(&shim)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_utf8_path() -> Result<(), Box<dyn Error>> {
+        let file_name = std::path::PathBuf::from(OsStr::from_bytes(b"/tmp/test_non_utf8_\xff.txt"));
+        write_user(&file_name, b"Hello")?;
+        append_user(&file_name, b", World!")?;
+        assert_eq!(read_user(&file_name)?, b"Hello, World!");
+        assert_eq!(read_try(&file_name)?, b"Hello, World!");
+        if has_root() {
+            assert!(!metadata(&file_name)?.uid.is_root());
+        }
+        std::fs::remove_file(&file_name)?;
+        Ok(())
+    }
+
     #[test]
+    #[allow(clippy::needless_borrow, clippy::needless_borrows_for_generic_args)] // 元のテストのまま残す
     fn test_set_allow_sudo() -> Result<(), Box<dyn Error>> {
         if !has_root() && !is_root() {
             let file_name = "/tmp/test_root_file.txt";
@@ -0,0 +1,59 @@
+use std::marker::PhantomData;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+/// Owner thread and recursion count of the privilege lock.
+struct LockState {
+    owner: Option<ThreadId>,
+    count: usize,
+}
+
+static STATE: Mutex<LockState> = Mutex::new(LockState {
+    owner: None,
+    count: 0,
+});
+static RELEASED: Condvar = Condvar::new();
+
+fn state() -> MutexGuard<'static, LockState> {
+    // panic で poison されても状態自体は壊れていないので使い続ける
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Process-wide lock that serializes privilege transitions.
+///
+/// `seteuid` and friends change the credentials of every thread in the process,
+/// so all `change_*` functions, guards and `*_user`/`*_root` operations hold this
+/// lock while they run. The lock is reentrant: a thread that already holds it can
+/// acquire it again, so it can be held around several operations to run them
+/// with consistent credentials.
+pub struct PrivilegeLock {
+    // 取得したスレッドで解放する必要があるので Send にしない
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PrivilegeLock {
+    /// Acquires the lock, blocking while another thread holds it.
+    pub fn acquire() -> Self {
+        let me = thread::current().id();
+        let mut state = state();
+        while state.owner.is_some_and(|owner| owner != me) {
+            state = RELEASED.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.owner = Some(me);
+        state.count += 1;
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for PrivilegeLock {
+    fn drop(&mut self) {
+        let mut state = state();
+        state.count -= 1;
+        if state.count == 0 {
+            state.owner = None;
+            RELEASED.notify_one();
+        }
+    }
+}
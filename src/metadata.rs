@@ -0,0 +1,243 @@
+use crate::{command_timeout, elevator, helper, is_root, with_root, with_user, Elevator, Error};
+use nix::sys::stat::SFlag;
+use nix::unistd::{Gid, Uid};
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::result::Result;
+use std::sync::Arc;
+
+/// Metadata of a file.
+///
+/// Unlike `std::fs::Metadata`, this can also be obtained through `sudo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// File type and permission bits (`st_mode`).
+    pub mode: u32,
+    /// Owner.
+    pub uid: Uid,
+    /// Group.
+    pub gid: Gid,
+    /// Size in bytes.
+    pub size: u64,
+    /// Last modification time in seconds since the epoch.
+    pub mtime: i64,
+}
+
+impl Metadata {
+    /// Returns the permission bits including setuid, setgid and sticky bits.
+    pub fn permissions(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// Checks if this is a regular file.
+    pub fn is_file(&self) -> bool {
+        self.file_type() == SFlag::S_IFREG
+    }
+
+    /// Checks if this is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == SFlag::S_IFDIR
+    }
+
+    /// Checks if this is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        self.file_type() == SFlag::S_IFLNK
+    }
+
+    fn file_type(&self) -> SFlag {
+        SFlag::from_bits_truncate(self.mode) & SFlag::S_IFMT
+    }
+
+    /// Parses the output of `stat -c '%f %u %g %s %Y'`.
+    fn parse_stat(output: &str) -> Option<Self> {
+        let mut fields = output.split_whitespace();
+        let mode = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let uid = fields.next()?.parse().ok()?;
+        let gid = fields.next()?.parse().ok()?;
+        let size = fields.next()?.parse().ok()?;
+        let mtime = fields.next()?.parse().ok()?;
+        Some(Self {
+            mode,
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(gid),
+            size,
+            mtime,
+        })
+    }
+}
+
+impl From<std::fs::Metadata> for Metadata {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        Self {
+            mode: metadata.mode(),
+            uid: Uid::from_raw(metadata.uid()),
+            gid: Gid::from_raw(metadata.gid()),
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+        }
+    }
+}
+
+/// Reads the metadata of a file, following symbolic links.
+pub fn metadata(filename: impl AsRef<Path>) -> Result<Metadata, Error> {
+    let filename = filename.as_ref();
+    let metadata = std::fs::metadata(filename).map_err(|e| Error::io(filename, e))?;
+    Ok(metadata.into())
+}
+
+/// Reads the metadata of a file using `sudo` permissions.
+pub fn metadata_sudo(filename: impl AsRef<Path>) -> Result<Metadata, Error> {
+    let filename = filename.as_ref();
+    // helper が起動していれば helper で取得する
+    if let Some(result) = helper::with_helper(|helper| helper.stat(filename)) {
+        return result;
+    }
+
+    // `stat` コマンドで mode(16進), uid, gid, size, mtime を取得する
+    let args = ["-L", "-c", "%f %u %g %s %Y", "--"]
+        .map(OsStr::new)
+        .into_iter()
+        .chain([filename.as_os_str()])
+        .collect::<Vec<_>>();
+    let output = elevator::run_elevated(OsStr::new("stat"), &args, None, command_timeout())?;
+    if !output.status.success() {
+        return Err(Error::sudo_failed(&output));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Metadata::parse_stat(&stdout).ok_or_else(|| Error::UnexpectedOutput(stdout.trim().to_string()))
+}
+
+/// Reads the metadata of a file using user permissions.
+pub fn metadata_user(filename: impl AsRef<Path>) -> Result<Metadata, Error> {
+    let filename = filename.as_ref();
+    with_user(|| metadata(filename))
+}
+
+/// Reads the metadata of a file using root permissions, or `sudo` if allowed.
+pub fn metadata_root(filename: impl AsRef<Path>) -> Result<Metadata, Error> {
+    let filename = filename.as_ref();
+    with_root(|| metadata(filename), || metadata_sudo(filename))
+}
+
+/// Reads the metadata of a file and tries to use root permissions if that fails.
+pub fn metadata_try(filename: impl AsRef<Path>) -> Result<Metadata, Error> {
+    let filename = filename.as_ref();
+    let result = metadata(filename);
+    if result.is_err() && !is_root() {
+        metadata_root(filename)
+    } else {
+        result
+    }
+}
+
+/// Checks if a file exists, following symbolic links.
+///
+/// Returns `Ok(false)` only if the file is not there. If it cannot be checked, for example
+/// because a parent directory cannot be searched, an error is returned instead.
+pub fn exists(filename: impl AsRef<Path>) -> Result<bool, Error> {
+    let filename = filename.as_ref();
+    match std::fs::metadata(filename) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::io(filename, e)),
+    }
+}
+
+// elevator 経由で実行するスクリプト
+// $1: file
+const EXISTS_SCRIPT: &str = r#"if [ -e "$1" ]; then echo yes; else echo no; fi"#;
+
+/// Checks if a file exists through `elevator` (or the configured one).
+fn exists_elevated(elevator: Option<&Arc<dyn Elevator>>, filename: &Path) -> Result<bool, Error> {
+    // 終了ステータスだと sudo 自体の失敗と区別できないので出力で判断する
+    let output = elevator::run_script(elevator, EXISTS_SCRIPT, &[filename.as_os_str()], None)?;
+    match output.stdout.as_slice() {
+        b"yes\n" => Ok(true),
+        b"no\n" => Ok(false),
+        stdout => Err(Error::UnexpectedOutput(
+            String::from_utf8_lossy(stdout).trim().to_string(),
+        )),
+    }
+}
+
+/// Checks if a file exists using `sudo` permissions.
+pub fn exists_sudo(filename: impl AsRef<Path>) -> Result<bool, Error> {
+    exists_elevated(None, filename.as_ref())
+}
+
+/// Checks if a file exists using user permissions.
+pub fn exists_user(filename: impl AsRef<Path>) -> Result<bool, Error> {
+    let filename = filename.as_ref();
+    with_user(|| exists(filename))
+}
+
+/// Checks if a file exists using root permissions, or `sudo` if allowed.
+///
+/// Unlike `exists_user`, this also sees files under directories only root can search.
+pub fn exists_root(filename: impl AsRef<Path>) -> Result<bool, Error> {
+    let filename = filename.as_ref();
+    with_root(|| exists(filename), || exists_sudo(filename))
+}
+
+/// Checks if a file exists and tries to use root permissions if it cannot be checked.
+pub fn exists_try(filename: impl AsRef<Path>) -> Result<bool, Error> {
+    let filename = filename.as_ref();
+    let result = exists(filename);
+    if result.is_err() && !is_root() {
+        exists_root(filename)
+    } else {
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::has_root;
+
+    #[test]
+    fn test_parse_stat() {
+        let metadata = Metadata::parse_stat("81a4 0 0 14 1700000000\n").unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.permissions(), 0o644);
+        assert!(metadata.uid.is_root());
+        assert_eq!(metadata.size, 14);
+        assert_eq!(metadata.mtime, 1700000000);
+        assert!(Metadata::parse_stat("81a4 0").is_none());
+    }
+
+    #[test]
+    fn test_exists() -> Result<(), Box<dyn std::error::Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_exists",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(crate::Sudo::with_program(&shim));
+
+        let dir = std::env::temp_dir().join(format!("test_exists_{}", std::process::id()));
+        let file_name = dir.join("file");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&file_name, b"")?;
+        for name in [&file_name, &dir.join("missing")] {
+            let expected = name == &file_name;
+            assert_eq!(exists(name)?, expected);
+            assert_eq!(exists_elevated(Some(&elevator), name)?, expected);
+        }
+
+        // user から見えないディレクトリの中は、無いのではなくエラーになる
+        if has_root() && crate::target_user().is_ok() {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+            assert!(exists_user(&file_name).is_err());
+            assert!(exists_root(&file_name)?);
+            assert!(!exists_root(dir.join("missing"))?);
+            assert!(metadata_user(&file_name).is_err());
+            assert!(metadata_root(&file_name)?.is_file());
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use crate::{is_root, with_root, with_user, Error};
+use std::result::Result;
+
+/// Which permissions an operation runs with.
+///
+/// Each variant corresponds to the suffix of the `read_*`/`write_*` function families.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Mode {
+    /// Current permissions, without changing anything.
+    #[default]
+    Current,
+    /// User permissions (`*_user`).
+    User,
+    /// Root permissions, or `sudo` if allowed (`*_root`).
+    Root,
+    /// Always through `sudo` or the configured elevator (`*_sudo`).
+    Sudo,
+    /// Current permissions first, then root permissions if that fails (`*_try`).
+    Try,
+}
+
+impl Mode {
+    /// Runs `f` directly with the permissions of this mode, or `sudo` where an elevator is used.
+    pub(crate) fn run<T>(
+        self,
+        f: impl Fn() -> Result<T, Error>,
+        sudo: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        match self {
+            Mode::Current => f(),
+            Mode::User => with_user(f),
+            Mode::Root => with_root(f, sudo),
+            Mode::Sudo => sudo(),
+            Mode::Try => {
+                // 失敗した場合のみ root で再実行する
+                let result = f();
+                if result.is_err() && !is_root() {
+                    with_root(f, sudo)
+                } else {
+                    result
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::elevator;
+use crate::{is_root, with_root, with_user, Elevator, Error};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::result::Result;
+use std::sync::Arc;
+
+/// Reads up to `len` bytes at `offset` of a file.
+///
+/// Fewer bytes are returned only if the end of the file is reached.
+pub fn read_at(filename: impl AsRef<Path>, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    let with_path = |e| Error::io(filename, e);
+    let file = File::open(filename).map_err(with_path)?;
+    let mut data = vec![0u8; len];
+    let mut done = 0;
+    while done < len {
+        match file.read_at(&mut data[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(with_path(e)),
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+/// Concatenates `key=` and `value` into a `dd` operand.
+pub(crate) fn operand(key: &str, value: impl AsRef<OsStr>) -> OsString {
+    let mut operand = OsString::from(key);
+    operand.push("=");
+    operand.push(value);
+    operand
+}
+
+/// Reads at an offset with `dd` through `elevator` (or the configured one).
+fn read_at_elevated(
+    elevator: Option<&Arc<dyn Elevator>>,
+    filename: &Path,
+    offset: u64,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+
+    // skip/count をブロック数ではなくバイト数として扱わせる
+    let args = [
+        operand("if", filename),
+        operand("bs", len.min(1 << 20).to_string()),
+        operand("skip", offset.to_string()),
+        operand("count", len.to_string()),
+        OsString::from("iflag=skip_bytes,count_bytes,fullblock"),
+        OsString::from("status=none"),
+    ];
+    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+    let output = elevator::run_checked(elevator, OsStr::new("dd"), &args, None)?;
+    Ok(output.stdout)
+}
+
+/// Reads up to `len` bytes at `offset` of a file using `sudo` permissions.
+pub fn read_at_sudo(filename: impl AsRef<Path>, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    read_at_elevated(None, filename.as_ref(), offset, len)
+}
+
+/// Reads up to `len` bytes at `offset` of a file using user permissions.
+pub fn read_at_user(filename: impl AsRef<Path>, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    with_user(|| read_at(filename, offset, len))
+}
+
+/// Reads up to `len` bytes at `offset` of a file using root permissions, or `sudo` if allowed.
+pub fn read_at_root(filename: impl AsRef<Path>, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    with_root(
+        || read_at(filename, offset, len),
+        || read_at_sudo(filename, offset, len),
+    )
+}
+
+/// Reads at an offset and tries to use root permissions if the initial read fails.
+pub fn read_at_try(filename: impl AsRef<Path>, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let filename = filename.as_ref();
+    let result = read_at(filename, offset, len);
+    if result.is_err() && !is_root() {
+        read_at_root(filename, offset, len)
+    } else {
+        result
+    }
+}
+
+/// Writes binary data at `offset` of an existing file without truncating it.
+pub fn write_at(filename: impl AsRef<Path>, offset: u64, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    let with_path = |e| Error::io(filename, e);
+    let file = OpenOptions::new()
+        .write(true)
+        .open(filename)
+        .map_err(with_path)?;
+    file.write_all_at(data, offset).map_err(with_path)?;
+    Ok(())
+}
+
+/// Writes at an offset with `dd` through `elevator` (or the configured one).
+fn write_at_elevated(
+    elevator: Option<&Arc<dyn Elevator>>,
+    filename: &Path,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), Error> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // レジスタのようなファイルに分割して書き込まないように1ブロックでまとめて書く
+    let args = [
+        operand("of", filename),
+        operand("bs", data.len().to_string()),
+        operand("seek", offset.to_string()),
+        OsString::from("count=1"),
+        OsString::from("iflag=fullblock"),
+        OsString::from("oflag=seek_bytes"),
+        OsString::from("conv=notrunc,nocreat"),
+        OsString::from("status=none"),
+    ];
+    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+    elevator::run_checked(elevator, OsStr::new("dd"), &args, Some(data))?;
+    Ok(())
+}
+
+/// Writes binary data at `offset` of a file using `sudo` permissions.
+pub fn write_at_sudo(filename: impl AsRef<Path>, offset: u64, data: &[u8]) -> Result<(), Error> {
+    write_at_elevated(None, filename.as_ref(), offset, data)
+}
+
+/// Writes binary data at `offset` of a file using user permissions.
+pub fn write_at_user(filename: impl AsRef<Path>, offset: u64, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_user(|| write_at(filename, offset, data))
+}
+
+/// Writes binary data at `offset` of a file using root permissions, or `sudo` if allowed.
+pub fn write_at_root(filename: impl AsRef<Path>, offset: u64, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    with_root(
+        || write_at(filename, offset, data),
+        || write_at_sudo(filename, offset, data),
+    )
+}
+
+/// Writes at an offset and tries to use root permissions if the initial write fails.
+pub fn write_at_try(filename: impl AsRef<Path>, offset: u64, data: &[u8]) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    let result = write_at(filename, offset, data);
+    if result.is_err() && !is_root() {
+        write_at_root(filename, offset, data)
+    } else {
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read, Sudo};
+
+    #[test]
+    fn test_read_write_at() -> Result<(), Box<dyn std::error::Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_offset",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let file_name =
+            std::env::temp_dir().join(format!("test_offset_{}.bin", std::process::id()));
+
+        std::fs::write(&file_name, b"0123456789")?;
+        write_at(&file_name, 2, b"ab")?;
+        assert_eq!(read(&file_name)?, b"01ab456789");
+        assert_eq!(read_at(&file_name, 1, 4)?, b"1ab4");
+        assert_eq!(read_at(&file_name, 8, 4)?, b"89");
+
+        // sudo の場合も dd で同じ結果になる
+        write_at_elevated(Some(&elevator), &file_name, 6, b"cd")?;
+        assert_eq!(read(&file_name)?, b"01ab45cd89");
+        assert_eq!(read_at_elevated(Some(&elevator), &file_name, 5, 3)?, b"5cd");
+        assert_eq!(read_at_elevated(Some(&elevator), &file_name, 8, 4)?, b"89");
+        assert_eq!(read_at_elevated(Some(&elevator), &file_name, 0, 0)?, b"");
+
+        // 存在しないファイルは作成しない
+        let missing = file_name.with_extension("missing");
+        assert!(write_at(&missing, 0, b"x").is_err());
+        assert!(write_at_elevated(Some(&elevator), &missing, 0, b"x").is_err());
+        assert!(!missing.exists());
+
+        std::fs::remove_file(&file_name)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{giveback, helper, is_root, with_root, with_user, Error};
+use std::fs::File;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::result::Result;
+
+// helper に渡す時のフラグ
+const FLAG_READ: u8 = 1 << 0;
+const FLAG_WRITE: u8 = 1 << 1;
+const FLAG_APPEND: u8 = 1 << 2;
+const FLAG_TRUNCATE: u8 = 1 << 3;
+const FLAG_CREATE: u8 = 1 << 4;
+const FLAG_CREATE_NEW: u8 = 1 << 5;
+
+/// Options for opening a file with `open_root` and friends.
+///
+/// This works like `std::fs::OpenOptions`, but can also be sent to the privileged helper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    mode: u32,
+    custom_flags: i32,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptions {
+    /// Creates options with every flag cleared, like `std::fs::OpenOptions::new`.
+    pub fn new() -> Self {
+        Self {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            mode: 0o666,
+            custom_flags: 0,
+        }
+    }
+
+    /// Sets the option for read access.
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    /// Sets the option for write access.
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Sets the option for append mode.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Sets the option for truncating the file.
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Sets the option to create the file if it does not exist.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Sets the option to always create a new file.
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Sets the permission bits used when a file is created.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets additional flags passed to `open(2)`, such as `O_SYNC`.
+    pub fn custom_flags(&mut self, flags: i32) -> &mut Self {
+        self.custom_flags = flags;
+        self
+    }
+
+    /// Converts to `std::fs::OpenOptions`.
+    pub(crate) fn to_std(self) -> std::fs::OpenOptions {
+        let mut options = std::fs::OpenOptions::new();
+        options
+            .read(self.read)
+            .write(self.write)
+            .append(self.append)
+            .truncate(self.truncate)
+            .create(self.create)
+            .create_new(self.create_new)
+            .mode(self.mode)
+            .custom_flags(self.custom_flags);
+        options
+    }
+
+    /// Runs `f` and records `filename` for `give_back_all` if these options may create it.
+    fn track(
+        &self,
+        filename: &Path,
+        f: impl FnOnce() -> Result<File, Error>,
+    ) -> Result<File, Error> {
+        if self.create || self.create_new {
+            giveback::track(filename, f)
+        } else {
+            f()
+        }
+    }
+
+    /// Encodes the options into flags, mode and custom flags for the helper.
+    pub(crate) fn encode(&self) -> (u8, u32, i32) {
+        let flags = [
+            (self.read, FLAG_READ),
+            (self.write, FLAG_WRITE),
+            (self.append, FLAG_APPEND),
+            (self.truncate, FLAG_TRUNCATE),
+            (self.create, FLAG_CREATE),
+            (self.create_new, FLAG_CREATE_NEW),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        (flags, self.mode, self.custom_flags)
+    }
+
+    /// Decodes the options encoded by `encode`.
+    pub(crate) fn decode(flags: u8, mode: u32, custom_flags: i32) -> Self {
+        Self {
+            read: flags & FLAG_READ != 0,
+            write: flags & FLAG_WRITE != 0,
+            append: flags & FLAG_APPEND != 0,
+            truncate: flags & FLAG_TRUNCATE != 0,
+            create: flags & FLAG_CREATE != 0,
+            create_new: flags & FLAG_CREATE_NEW != 0,
+            mode,
+            custom_flags,
+        }
+    }
+}
+
+/// Opens a file with the given options.
+pub fn open(filename: impl AsRef<Path>, options: &OpenOptions) -> Result<File, Error> {
+    let filename = filename.as_ref();
+    options
+        .to_std()
+        .open(filename)
+        .map_err(|e| Error::io(filename, e))
+}
+
+/// Opens a file using `sudo` permissions.
+///
+/// The file is opened by the privileged helper and its file descriptor is passed back, so
+/// `main` must call `run_helper_if_requested` first, or `Error::Helper` is returned. If the
+/// helper is not running, it is started only for this call.
+pub fn open_sudo(filename: impl AsRef<Path>, options: &OpenOptions) -> Result<File, Error> {
+    let filename = filename.as_ref();
+    options.track(filename, || {
+        helper::with_helper_or_launch(|helper| helper.open(filename, options))
+    })
+}
+
+/// Opens a file using user permissions.
+pub fn open_user(filename: impl AsRef<Path>, options: &OpenOptions) -> Result<File, Error> {
+    let filename = filename.as_ref();
+    with_user(|| open(filename, options))
+}
+
+/// Opens a file using root permissions, or `sudo` if allowed.
+///
+/// The returned file stays usable after the permissions are switched back.
+/// Through `sudo`, the file is opened by the privileged helper as in `open_sudo`, so `main`
+/// must call `run_helper_if_requested` first.
+pub fn open_root(filename: impl AsRef<Path>, options: &OpenOptions) -> Result<File, Error> {
+    let filename = filename.as_ref();
+    options.track(filename, || {
+        with_root(|| open(filename, options), || open_sudo(filename, options))
+    })
+}
+
+/// Opens a file and tries to use root permissions if the initial open fails.
+pub fn open_try(filename: impl AsRef<Path>, options: &OpenOptions) -> Result<File, Error> {
+    let filename = filename.as_ref();
+    options.track(filename, || {
+        let result = open(filename, options);
+        if result.is_err() && !is_root() {
+            open_root(filename, options)
+        } else {
+            result
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{has_root, read, RootGuard};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_open_options() {
+        let mut options = OpenOptions::new();
+        options.read(true).create_new(true).mode(0o600);
+        let (flags, mode, custom_flags) = options.encode();
+        assert_eq!(OpenOptions::decode(flags, mode, custom_flags), options);
+    }
+
+    #[test]
+    fn test_open_root() -> Result<(), Box<dyn std::error::Error>> {
+        if has_root() && crate::target_user().is_ok() {
+            // root だけが読み書きできるファイルを作る
+            let file_name = format!("/tmp/test_open_root_{}.txt", std::process::id());
+            {
+                let _root = RootGuard::new()?;
+                std::fs::write(&file_name, b"secret")?;
+                std::fs::set_permissions(&file_name, std::fs::Permissions::from_mode(0o600))?;
+            }
+
+            // root で開いた fd は user に戻っても使える
+            let mut file = open_root(&file_name, OpenOptions::new().read(true).write(true))?;
+            crate::with_user(|| {
+                assert!(!crate::is_root());
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).unwrap();
+                assert_eq!(data, b"secret");
+                file.write_all(b", written by user").unwrap();
+                Ok(())
+            })?;
+            assert!(open_user(&file_name, OpenOptions::new().read(true)).is_err());
+            drop(file);
+            assert_eq!(read(&file_name)?, b"secret, written by user");
+
+            std::fs::remove_file(&file_name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{elevator, giveback};
+use crate::{Elevator, Error, Mode};
+use nix::unistd::{Gid, Uid};
+use std::ffi::{OsStr, OsString};
+use std::fs::{OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{fchown, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::result::Result;
+use std::sync::Arc;
+
+/// Options for `write_with`.
+///
+/// The same options are applied whether the write is done directly or through `sudo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Owner to set on the file, or `None` to leave it as is.
+    pub owner: Option<Uid>,
+    /// Group to set on the file, or `None` to leave it as is.
+    pub group: Option<Gid>,
+    /// Permission bits to set on the file, whether it is created or already exists.
+    pub perm: Option<u32>,
+    /// Creates the file if it does not exist.
+    pub create: bool,
+    /// Truncates the file before writing. Otherwise the data overwrites it from the start.
+    pub truncate: bool,
+    /// Umask applied when a file is created and `perm` is not given.
+    pub umask: Option<u32>,
+}
+
+impl Default for WriteOptions {
+    /// Same as `write`: creates or truncates the file and leaves the owner and permissions as is.
+    fn default() -> Self {
+        Self {
+            owner: None,
+            group: None,
+            perm: None,
+            create: true,
+            truncate: true,
+            umask: None,
+        }
+    }
+}
+
+impl WriteOptions {
+    /// Returns the permission bits to create a new file with.
+    fn create_mode(&self) -> u32 {
+        match (self.perm, self.umask) {
+            // 書き込みが終わるまで他のユーザーから読めないようにしておく
+            (Some(_), _) => 0o600,
+            (None, Some(umask)) => 0o666 & !umask,
+            (None, None) => 0o666,
+        }
+    }
+}
+
+/// Writes binary data to a file with the given options and permissions.
+pub fn write_with(
+    mode: Mode,
+    filename: impl AsRef<Path>,
+    data: &[u8],
+    options: &WriteOptions,
+) -> Result<(), Error> {
+    let filename = filename.as_ref();
+    let write = || {
+        mode.run(
+            || write_options(filename, data, options),
+            || write_options_elevated(None, filename, data, options),
+        )
+    };
+    match mode {
+        Mode::Root | Mode::Sudo | Mode::Try if options.create => giveback::track(filename, write),
+        _ => write(),
+    }
+}
+
+/// Writes binary data to a file with the given options using the current permissions.
+fn write_options(filename: &Path, data: &[u8], options: &WriteOptions) -> Result<(), Error> {
+    let with_path = |e| Error::io(filename, e);
+    let mut open = OpenOptions::new();
+    open.write(true)
+        .truncate(options.truncate)
+        .mode(options.create_mode());
+
+    // 新規作成かどうかで umask の適用が変わるので create_new で確かめる
+    let (mut file, created) = if options.create {
+        match open.clone().create_new(true).open(filename) {
+            Ok(file) => (file, true),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                (open.open(filename).map_err(with_path)?, false)
+            }
+            Err(e) => return Err(with_path(e)),
+        }
+    } else {
+        (open.open(filename).map_err(with_path)?, false)
+    };
+
+    // chown で setuid などが落ちるので chmod より先に行う
+    if options.owner.is_some() || options.group.is_some() {
+        fchown(
+            &file,
+            options.owner.map(Uid::as_raw),
+            options.group.map(Gid::as_raw),
+        )
+        .map_err(with_path)?;
+    }
+
+    // プロセスの umask に左右されないように作成後にもう一度設定する
+    let perm = match (options.perm, created) {
+        (Some(perm), _) => Some(perm),
+        (None, true) => options.umask.map(|_| options.create_mode()),
+        (None, false) => None,
+    };
+    if let Some(perm) = perm {
+        file.set_permissions(Permissions::from_mode(perm))
+            .map_err(with_path)?;
+    }
+
+    file.write_all(data).map_err(with_path)?;
+    Ok(())
+}
+
+// elevator 経由で実行するスクリプト
+// $1: file, $2: create, $3: truncate, $4: owner, $5: group, $6: perm, $7: umask
+const WRITE_SCRIPT: &str = r#"set -e
+if [ ! -e "$1" ]; then
+    if [ "$2" != 1 ]; then
+        echo "$1: No such file or directory" >&2
+        exit 1
+    fi
+    if [ -n "$6" ]; then umask 077; elif [ -n "$7" ]; then umask "$7"; fi
+    : >> "$1"
+fi
+if [ -n "$4" ]; then chown -- "$4" "$1"; fi
+if [ -n "$5" ]; then chgrp -- "$5" "$1"; fi
+if [ -n "$6" ]; then chmod -- "$6" "$1"; fi
+if [ "$3" = 1 ]; then
+    cat > "$1"
+else
+    dd of="$1" conv=notrunc status=none
+fi
+"#;
+
+/// Writes binary data to a file with the given options through `elevator` (or the configured one).
+fn write_options_elevated(
+    elevator: Option<&Arc<dyn Elevator>>,
+    filename: &Path,
+    data: &[u8],
+    options: &WriteOptions,
+) -> Result<(), Error> {
+    let flag = |value: bool| OsString::from(if value { "1" } else { "0" });
+    let optional = |value: Option<String>| OsString::from(value.unwrap_or_default());
+    let args = [
+        filename.as_os_str().to_os_string(),
+        flag(options.create),
+        flag(options.truncate),
+        // 先頭の + で数値として扱わせ、ユーザー名やグループ名として解釈されないようにする
+        optional(options.owner.map(|uid| format!("+{}", uid))),
+        optional(options.group.map(|gid| format!("+{}", gid))),
+        optional(options.perm.map(|perm| format!("{:04o}", perm))),
+        optional(options.umask.map(|umask| format!("{:04o}", umask))),
+    ];
+    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+
+    // データは標準入力で渡す
+    elevator::run_script(elevator, WRITE_SCRIPT, &args, Some(data))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{has_root, metadata, read, Sudo};
+
+    /// Writes with `options` both directly and through a pass-through elevator.
+    fn write_both(
+        name: &str,
+        data: &[u8],
+        options: &WriteOptions,
+    ) -> Vec<Result<crate::Metadata, Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_options",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let direct = std::env::temp_dir().join(format!("{}_direct_{}", name, std::process::id()));
+        let sudo = std::env::temp_dir().join(format!("{}_sudo_{}", name, std::process::id()));
+        let results = vec![
+            write_options(&direct, data, options).and_then(|_| metadata(&direct)),
+            write_options_elevated(Some(&elevator), &sudo, data, options)
+                .and_then(|_| metadata(&sudo)),
+        ];
+        for (path, result) in [&direct, &sudo].iter().zip(&results) {
+            if result.is_ok() {
+                assert_eq!(read(path).unwrap(), data);
+            }
+            let _ = std::fs::remove_file(path);
+        }
+        results
+    }
+
+    #[test]
+    fn test_write_options() -> Result<(), Box<dyn std::error::Error>> {
+        // perm は作成時にもプロセスの umask に関係なく設定される
+        let options = WriteOptions {
+            perm: Some(0o640),
+            ..Default::default()
+        };
+        for result in write_both("test_write_options_perm", b"secret", &options) {
+            assert_eq!(result?.permissions(), 0o640);
+        }
+
+        let options = WriteOptions {
+            umask: Some(0o027),
+            ..Default::default()
+        };
+        for result in write_both("test_write_options_umask", b"log", &options) {
+            assert_eq!(result?.permissions(), 0o640);
+        }
+
+        // create しない場合は存在しないファイルには書き込めない
+        let options = WriteOptions {
+            create: false,
+            ..Default::default()
+        };
+        for result in write_both("test_write_options_create", b"none", &options) {
+            assert!(result.is_err());
+        }
+
+        if has_root() {
+            let options = WriteOptions {
+                owner: Some(Uid::from_raw(1234)),
+                group: Some(Gid::from_raw(5678)),
+                perm: Some(0o600),
+                ..Default::default()
+            };
+            for result in write_both("test_write_options_owner", b"owned", &options) {
+                let metadata = result?;
+                assert_eq!(metadata.uid, Uid::from_raw(1234));
+                assert_eq!(metadata.gid, Gid::from_raw(5678));
+                assert_eq!(metadata.permissions(), 0o600);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_options_no_truncate() -> Result<(), Box<dyn std::error::Error>> {
+        let shim = crate::tests::write_shim(
+            "sudo_no_truncate",
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nexec \"$@\"\n",
+        );
+        let elevator: Arc<dyn Elevator> = Arc::new(Sudo::with_program(&shim));
+        let options = WriteOptions {
+            truncate: false,
+            ..Default::default()
+        };
+
+        let file_name = format!(
+            "/tmp/test_write_options_no_truncate_{}.txt",
+            std::process::id()
+        );
+        std::fs::write(&file_name, b"Hello, World!")?;
+        write_options(Path::new(&file_name), b"Jelly", &options)?;
+        assert_eq!(read(&file_name)?, b"Jelly, World!");
+        write_options_elevated(Some(&elevator), Path::new(&file_name), b"Hi", &options)?;
+        assert_eq!(read(&file_name)?, b"Hilly, World!");
+        std::fs::remove_file(&file_name)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use crate::Error;
+use nix::unistd::{Gid, Uid, User};
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::result::Result;
+use std::sync::Mutex;
+
+/// The invoking user that user mode changes to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetUser {
+    /// User ID.
+    pub uid: Uid,
+    /// Primary group ID.
+    pub gid: Gid,
+    /// User name, used to look up supplementary groups.
+    pub name: Option<String>,
+}
+
+impl TargetUser {
+    /// Creates a target user, looking up the name in the user database.
+    pub fn new(uid: Uid, gid: Gid) -> Self {
+        let name = User::from_uid(uid).ok().flatten().map(|user| user.name);
+        Self { uid, gid, name }
+    }
+
+    /// Looks up a user by UID in the user database.
+    fn from_uid(uid: Uid) -> Result<Option<Self>, Error> {
+        Ok(User::from_uid(uid)?.map(Self::from))
+    }
+
+    /// Looks up a user by name in the user database.
+    fn from_name(name: &str) -> Result<Option<Self>, Error> {
+        Ok(User::from_name(name)?.map(Self::from))
+    }
+}
+
+impl From<User> for TargetUser {
+    fn from(user: User) -> Self {
+        Self {
+            uid: user.uid,
+            gid: user.gid,
+            name: Some(user.name),
+        }
+    }
+}
+
+/// A function that tries to determine the invoking user.
+///
+/// Returns `Ok(None)` if it does not apply, so that the next resolver is tried.
+pub type UserResolver = fn() -> Result<Option<TargetUser>, Error>;
+
+/// Environment variable that overrides the invoking user (`name`, `uid` or `uid:gid`).
+pub const TARGET_USER_ENV: &str = "JELLY_UIDMNG_USER";
+
+static TARGET_USER: Mutex<Option<TargetUser>> = Mutex::new(None);
+static USER_RESOLVER: Mutex<Option<UserResolver>> = Mutex::new(None);
+
+/// Sets the user that user mode changes to, overriding every resolver.
+pub fn set_target_user(uid: Uid, gid: Gid) {
+    *TARGET_USER.lock().unwrap() = Some(TargetUser::new(uid, gid));
+}
+
+/// Clears the user set by `set_target_user`.
+pub fn clear_target_user() {
+    *TARGET_USER.lock().unwrap() = None;
+}
+
+/// Sets a custom resolver that is tried before the built-in ones.
+pub fn set_user_resolver(resolver: Option<UserResolver>) {
+    *USER_RESOLVER.lock().unwrap() = resolver;
+}
+
+/// Determines the invoking user that user mode changes to.
+///
+/// The user is taken from the first of these that applies:
+/// `set_target_user`, `JELLY_UIDMNG_USER`, the resolver set by `set_user_resolver`,
+/// `SUDO_UID`/`SUDO_GID`, `DOAS_USER`, `PKEXEC_UID`, `/proc/self/loginuid`
+/// and the owner of the controlling TTY.
+pub fn target_user() -> Result<TargetUser, Error> {
+    // 明示的に指定されたユーザーを優先する
+    // 指定されていれば環境変数は見ない (不正な値でもエラーにしない)
+    let explicit = TARGET_USER.lock().unwrap().clone();
+    let user = match explicit {
+        Some(user) => Some(user),
+        None => resolve_env()?,
+    };
+    if let Some(user) = user {
+        if user.uid.is_root() {
+            return Err(Error::InvalidTargetUser(user.uid.to_string()));
+        }
+        return Ok(user);
+    }
+
+    let custom = *USER_RESOLVER.lock().unwrap();
+    let resolvers: [Option<UserResolver>; 6] = [
+        custom,
+        Some(resolve_sudo),
+        Some(resolve_doas),
+        Some(resolve_pkexec),
+        Some(resolve_loginuid),
+        Some(resolve_tty),
+    ];
+    for resolver in resolvers.into_iter().flatten() {
+        if let Some(user) = resolver()? {
+            // root に移行しても意味がないので root は採用しない
+            if !user.uid.is_root() {
+                return Ok(user);
+            }
+        }
+    }
+    Err(Error::NoTargetUser)
+}
+
+/// Parses a user given as `name`, `uid` or `uid:gid`.
+fn parse_user_spec(spec: &str) -> Result<TargetUser, Error> {
+    let invalid = || Error::InvalidTargetUser(spec.to_string());
+    if let Some((uid, gid)) = spec.split_once(':') {
+        let uid = uid.parse::<u32>().map_err(|_| invalid())?;
+        let gid = gid.parse::<u32>().map_err(|_| invalid())?;
+        return Ok(TargetUser::new(Uid::from_raw(uid), Gid::from_raw(gid)));
+    }
+    let user = match spec.parse::<u32>() {
+        Ok(uid) => TargetUser::from_uid(Uid::from_raw(uid))?,
+        Err(_) => TargetUser::from_name(spec)?,
+    };
+    user.ok_or_else(invalid)
+}
+
+/// Resolves the user from `JELLY_UIDMNG_USER`.
+fn resolve_env() -> Result<Option<TargetUser>, Error> {
+    match env::var(TARGET_USER_ENV) {
+        Ok(spec) => parse_user_spec(&spec).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Resolves the user from `SUDO_UID`, `SUDO_GID` and `SUDO_USER`.
+fn resolve_sudo() -> Result<Option<TargetUser>, Error> {
+    Ok(sudo_user(
+        env::var("SUDO_UID").ok(),
+        env::var("SUDO_GID").ok(),
+        env::var("SUDO_USER").ok(),
+    ))
+}
+
+/// Makes the user from the values of `SUDO_UID`, `SUDO_GID` and `SUDO_USER`.
+///
+/// Returns `None` unless both IDs are set and valid, so that the next resolver is tried.
+fn sudo_user(uid: Option<String>, gid: Option<String>, name: Option<String>) -> Option<TargetUser> {
+    // 片方しか無い、または数値でない場合は sudo の情報は使わない
+    let uid = Uid::from_raw(uid?.parse().ok()?);
+    let gid = Gid::from_raw(gid?.parse().ok()?);
+
+    // root から sudo した場合は他の方法で元のユーザーを探す
+    if uid.is_root() {
+        return None;
+    }
+    Some(TargetUser { uid, gid, name })
+}
+
+/// Resolves the user from `DOAS_USER`.
+fn resolve_doas() -> Result<Option<TargetUser>, Error> {
+    match env::var("DOAS_USER") {
+        Ok(name) => TargetUser::from_name(&name),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Resolves the user from `PKEXEC_UID`.
+fn resolve_pkexec() -> Result<Option<TargetUser>, Error> {
+    match env::var("PKEXEC_UID").map(|uid| uid.parse::<u32>()) {
+        Ok(Ok(uid)) => TargetUser::from_uid(Uid::from_raw(uid)),
+        _ => Ok(None),
+    }
+}
+
+/// Resolves the user from the audit login UID.
+fn resolve_loginuid() -> Result<Option<TargetUser>, Error> {
+    // 未設定の場合は (uid_t)-1 になっている
+    match fs::read_to_string("/proc/self/loginuid").map(|uid| uid.trim().parse::<u32>()) {
+        Ok(Ok(uid)) if uid != u32::MAX => TargetUser::from_uid(Uid::from_raw(uid)),
+        _ => Ok(None),
+    }
+}
+
+/// Resolves the user from the owner of the controlling TTY.
+fn resolve_tty() -> Result<Option<TargetUser>, Error> {
+    // 標準入出力のいずれかが端末であればその所有者を使う
+    for fd in 0..3 {
+        let Ok(path) = fs::read_link(format!("/proc/self/fd/{}", fd)) else {
+            continue;
+        };
+        if !path.starts_with("/dev/pts") && !path.to_string_lossy().starts_with("/dev/tty") {
+            continue;
+        }
+        if let Ok(metadata) = fs::metadata(&path) {
+            return TargetUser::from_uid(Uid::from_raw(metadata.uid()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_user_spec() {
+        let user = parse_user_spec("1234:5678").unwrap();
+        assert_eq!(user.uid, Uid::from_raw(1234));
+        assert_eq!(user.gid, Gid::from_raw(5678));
+
+        let user = parse_user_spec("root").unwrap();
+        assert!(user.uid.is_root());
+        assert_eq!(user.name.as_deref(), Some("root"));
+
+        let user = parse_user_spec("0").unwrap();
+        assert_eq!(user.name.as_deref(), Some("root"));
+
+        assert!(matches!(
+            parse_user_spec("1234:abc"),
+            Err(Error::InvalidTargetUser(_))
+        ));
+    }
+
+    #[test]
+    fn test_sudo_user() {
+        let id = |value: &str| Some(value.to_string());
+        let user = sudo_user(id("1000"), id("100"), id("user")).unwrap();
+        assert_eq!(user.uid, Uid::from_raw(1000));
+        assert_eq!(user.gid, Gid::from_raw(100));
+        assert_eq!(user.name.as_deref(), Some("user"));
+
+        // 足りない、または不正な場合はエラーにせず次の方法に任せる
+        assert_eq!(sudo_user(id("1000"), None, None), None);
+        assert_eq!(sudo_user(None, id("100"), None), None);
+        assert_eq!(sudo_user(id("1000"), id("abc"), None), None);
+        assert_eq!(sudo_user(id("0"), id("0"), id("root")), None);
+    }
+
+    #[test]
+    fn test_set_target_user() {
+        // change_user() が途中で target_user() を参照しないようにする
+        let _lock = crate::PrivilegeLock::acquire();
+
+        set_target_user(Uid::from_raw(1234), Gid::from_raw(5678));
+        let user = target_user().unwrap();
+        assert_eq!(user.uid, Uid::from_raw(1234));
+        assert_eq!(user.gid, Gid::from_raw(5678));
+        clear_target_user();
+        assert_ne!(target_user().ok(), Some(user));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{
+    read, read_root, read_sudo, read_try, read_user, write, write_root, write_sudo, write_try,
+    write_user, Error,
+};
+use std::fmt::Display;
+use std::path::Path;
+use std::result::Result;
+use std::str::FromStr;
+
+/// Parses the content of an attribute file, ignoring surrounding whitespace and newlines.
+fn parse_value<T>(filename: &Path, data: Vec<u8>) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let parse_error =
+        |content: String, source: Box<dyn std::error::Error + Send + Sync>| Error::Parse {
+            path: filename.to_path_buf(),
+            content,
+            source,
+        };
+    let content = match String::from_utf8(data) {
+        Ok(content) => content,
+        Err(e) => {
+            let content = String::from_utf8_lossy(e.as_bytes()).into_owned();
+            return Err(parse_error(content, Box::new(e.utf8_error())));
+        }
+    };
+    content
+        .trim()
+        .parse()
+        .map_err(|e| parse_error(content.clone(), Box::new(e)))
+}
+
+/// Formats a value as an attribute file line.
+fn format_value(value: impl Display) -> Vec<u8> {
+    format!("{}\n", value).into_bytes()
+}
+
+/// Reads a file such as a sysfs attribute and parses its content as `T`.
+pub fn read_value<T>(filename: impl AsRef<Path>) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let filename = filename.as_ref();
+    parse_value(filename, read(filename)?)
+}
+
+/// Reads and parses a value using `sudo` permissions.
+pub fn read_value_sudo<T>(filename: impl AsRef<Path>) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let filename = filename.as_ref();
+    parse_value(filename, read_sudo(filename)?)
+}
+
+/// Reads and parses a value using user permissions.
+pub fn read_value_user<T>(filename: impl AsRef<Path>) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let filename = filename.as_ref();
+    parse_value(filename, read_user(filename)?)
+}
+
+/// Reads and parses a value using root permissions, or `sudo` if allowed.
+pub fn read_value_root<T>(filename: impl AsRef<Path>) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let filename = filename.as_ref();
+    parse_value(filename, read_root(filename)?)
+}
+
+/// Reads and parses a value, trying root permissions if the initial read fails.
+pub fn read_value_try<T>(filename: impl AsRef<Path>) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let filename = filename.as_ref();
+    parse_value(filename, read_try(filename)?)
+}
+
+/// Writes a value followed by a newline to a file such as a sysfs attribute.
+pub fn write_value(filename: impl AsRef<Path>, value: impl Display) -> Result<(), Error> {
+    write(filename, &format_value(value))
+}
+
+/// Writes a value followed by a newline using `sudo` permissions.
+pub fn write_value_sudo(filename: impl AsRef<Path>, value: impl Display) -> Result<(), Error> {
+    write_sudo(filename, &format_value(value))
+}
+
+/// Writes a value followed by a newline using user permissions.
+pub fn write_value_user(filename: impl AsRef<Path>, value: impl Display) -> Result<(), Error> {
+    write_user(filename, &format_value(value))
+}
+
+/// Writes a value followed by a newline using root permissions, or `sudo` if allowed.
+pub fn write_value_root(filename: impl AsRef<Path>, value: impl Display) -> Result<(), Error> {
+    write_root(filename, &format_value(value))
+}
+
+/// Writes a value followed by a newline, trying root permissions if the initial write fails.
+pub fn write_value_try(filename: impl AsRef<Path>, value: impl Display) -> Result<(), Error> {
+    write_try(filename, &format_value(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value() -> Result<(), Box<dyn std::error::Error>> {
+        let file_name = std::env::temp_dir().join(format!("test_value_{}", std::process::id()));
+
+        write_value(&file_name, 100_000_000)?;
+        assert_eq!(read(&file_name)?, b"100000000\n");
+        assert_eq!(read_value::<u32>(&file_name)?, 100_000_000);
+
+        std::fs::write(&file_name, b"  okay \n")?;
+        assert_eq!(read_value::<String>(&file_name)?, "okay");
+
+        // 失敗した場合はパスと元の内容が分かる
+        match read_value::<i32>(&file_name) {
+            Err(Error::Parse { path, content, .. }) => {
+                assert_eq!(path, file_name);
+                assert_eq!(content, "  okay \n");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        std::fs::write(&file_name, b"\xff\n")?;
+        assert!(matches!(
+            read_value::<String>(&file_name),
+            Err(Error::Parse { .. })
+        ));
+
+        std::fs::remove_file(&file_name)?;
+        Ok(())
+    }
+}